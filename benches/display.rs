@@ -0,0 +1,127 @@
+// Framebuffer benchmarks: drawing sprites directly, whole frames of a
+// sprite-heavy ROM at high instruction rates, and walking the pixels the
+// way a renderer does. The draw_row cases are also run on the old byte
+// per pixel framebuffer as a baseline.
+//
+//   cargo bench --bench display
+use chip8::display::{HIRES_DISPLAY_HEIGHT, HIRES_DISPLAY_WIDTH};
+use chip8::{Display, Platform, CHIP8};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+// Draws a 16x16 sprite (DXY0) at V0,V1, moves it along and loops, so
+// nearly every instruction is a draw.
+const SPRITES: &[u8] = &[
+    0x00, 0xFF, // 200: hires
+    0xA2, 0x10, // 202: I = 0x210
+    0xD0, 0x10, // 204: draw 16x16 at V0, V1
+    0x70, 0x05, // 206: V0 += 5
+    0x71, 0x03, // 208: V1 += 3
+    0xD0, 0x10, // 20A: draw again
+    0x12, 0x04, // 20C: loop
+    0x00, 0x00, // 20E
+    0xFF, 0xFF, 0x81, 0x81, 0xBD, 0xBD, 0xA5, 0xA5, 0xA5, 0xA5, 0xBD, 0xBD, 0x81, 0x81, 0xFF, 0xFF, // 210: sprite
+    0xFF, 0xFF, 0x81, 0x81, 0xBD, 0xBD, 0xA5, 0xA5, 0xA5, 0xA5, 0xBD, 0xBD, 0x81, 0x81, 0xFF, 0xFF,
+];
+
+// The byte per pixel framebuffer the packed one replaced, kept as is.
+struct Reference {
+    hires: bool,
+    pixels: [[u8; HIRES_DISPLAY_WIDTH]; HIRES_DISPLAY_HEIGHT],
+}
+
+impl Reference {
+    fn draw_row(&mut self, x: usize, y: usize, bits: u16, width: usize, plane: u8, clip: bool) -> bool {
+        let (screen_width, screen_height) = if self.hires { (128, 64) } else { (64, 32) };
+        if clip && y >= screen_height {
+            return false;
+        }
+        let y = y % screen_height;
+        let mut collision = false;
+        for bit in 0..width {
+            if clip && x + bit >= screen_width {
+                break;
+            }
+            if (bits >> (width - 1 - bit)) & 1 == 0 {
+                continue;
+            }
+            let pixel = &mut self.pixels[y][(x + bit) % screen_width];
+            collision |= *pixel & plane != 0;
+            *pixel ^= plane;
+        }
+        collision
+    }
+}
+
+fn draw_rows(c: &mut Criterion) {
+    let mut display = Display::default();
+    display.set_hires(true);
+    c.bench_function("draw_row 16 bits, wrapping", |b| {
+        let mut x = 0;
+        b.iter(|| {
+            x = (x + 7) % 128;
+            black_box(display.draw_row(black_box(x), 5, 0xF0F0, 16, 0b01, false))
+        })
+    });
+    c.bench_function("draw_row 8 bits, clipped", |b| {
+        let mut x = 0;
+        b.iter(|| {
+            x = (x + 7) % 128;
+            black_box(display.draw_row(black_box(x), 9, 0xA5, 8, 0b11, true))
+        })
+    });
+
+    let mut reference = Reference { hires: true, pixels: [[0; HIRES_DISPLAY_WIDTH]; HIRES_DISPLAY_HEIGHT] };
+    c.bench_function("draw_row 16 bits, wrapping, byte per pixel baseline", |b| {
+        let mut x = 0;
+        b.iter(|| {
+            x = (x + 7) % 128;
+            black_box(reference.draw_row(black_box(x), 5, 0xF0F0, 16, 0b01, false))
+        })
+    });
+    c.bench_function("draw_row 8 bits, clipped, byte per pixel baseline", |b| {
+        let mut x = 0;
+        b.iter(|| {
+            x = (x + 7) % 128;
+            black_box(reference.draw_row(black_box(x), 9, 0xA5, 8, 0b11, true))
+        })
+    });
+}
+
+fn run_frames(c: &mut Criterion) {
+    for instructions in [1_000, 10_000] {
+        let mut chip = CHIP8::new(Platform::XoChip);
+        chip.load_from_slice(SPRITES, None).unwrap();
+        c.bench_function(&format!("run_frame, {} sprite-heavy instructions", instructions), |b| {
+            b.iter(|| chip.run_frame(black_box(instructions)).unwrap())
+        });
+    }
+}
+
+fn walk_pixels(c: &mut Criterion) {
+    let mut chip = CHIP8::new(Platform::XoChip);
+    chip.load_from_slice(SPRITES, None).unwrap();
+    chip.run_frame(1_000).unwrap();
+    let display = chip.display();
+    c.bench_function("count lit pixels with get, hires", |b| {
+        b.iter(|| {
+            let mut lit = 0;
+            for y in 0..display.height() {
+                for x in 0..display.width() {
+                    lit += (display.get(x, y) != 0) as usize;
+                }
+            }
+            black_box(lit)
+        })
+    });
+    c.bench_function("count lit pixels with color_mask, hires", |b| {
+        b.iter(|| {
+            let lit: u32 = (0..display.height())
+                .map(|y| (1..4).map(|color| display.color_mask(y, color).count_ones()).sum::<u32>())
+                .sum();
+            black_box(lit)
+        })
+    });
+}
+
+criterion_group!(benches, draw_rows, run_frames, walk_pixels);
+criterion_main!(benches);
@@ -0,0 +1,946 @@
+use crate::chip8::{Instruction, PROGRAM_MEMORY_START};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+
+// Assembler for Octo source (https://github.com/JohnEarnest/Octo).
+//
+// Supported: labels, `:const`, `:alias`, `:macro`, `:calc`, `:byte`,
+// `:org`, `:call`, `:unpack`, every statement for CHIP-8, SUPER-CHIP and
+// XO-CHIP, `if ... then`, `if ... begin ... else ... end` and
+// `loop ... while ... again`. `:breakpoint`, `:monitor` and `:proto` are
+// accepted and ignored. `:calc` works on integers and, like Octo,
+// evaluates operators right to left with no precedence.
+//
+// Every instruction goes through `Instruction::encode`, so whatever is
+// assembled here decodes back to what was written.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+#[derive(Debug)]
+pub struct Assembly {
+    pub rom: Vec<u8>,
+    pub labels: BTreeMap<String, u16>,
+}
+
+impl Assembly {
+    // One label per line, "0x0202 main", ordered by address.
+    pub fn symbol_file(&self) -> String {
+        let mut labels: Vec<(&String, &u16)> = self.labels.iter().collect();
+        labels.sort_by_key(|&(name, address)| (*address, name.clone()));
+        labels.iter().map(|(name, address)| format!("{:#06X} {}\n", address, name)).collect()
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Assembly, AssembleError> {
+    let mut assembler = Assembler::new(source);
+    assembler.run()?;
+    Ok(Assembly {
+        rom: assembler.rom,
+        labels: assembler.labels,
+    })
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (number, line) in source.lines().enumerate() {
+        let code = line.split('#').next().unwrap_or("");
+        for word in code.split_whitespace() {
+            tokens.push_back(Token { text: word.to_string(), line: number + 1 });
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+    if digit.len() == 1 { u8::from_str_radix(digit, 16).ok() } else { None }
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<Token>,
+}
+
+// Fills in an address once the label it refers to is known.
+struct Fixup {
+    address: u16,
+    max: u16,
+    label: String,
+    line: usize,
+    make: Box<dyn Fn(u16) -> Vec<Instruction>>,
+}
+
+enum Block {
+    // Address of the jump past the `begin` half.
+    If { jump: u16 },
+    // Address of the jump past the `else` half.
+    Else { jump: u16 },
+    // Start of the loop and the jumps out of it left by `while`.
+    Loop { start: u16, exits: Vec<u16> },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    Register(u8),
+    Value(i64),
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    line: usize,
+    rom: Vec<u8>,
+    here: u16,
+    labels: BTreeMap<String, u16>,
+    constants: HashMap<String, i64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    blocks: Vec<Block>,
+    // Set until the first byte or label; see `enter`.
+    entry_pending: bool,
+}
+
+impl Assembler {
+    fn new(source: &str) -> Assembler {
+        Assembler {
+            tokens: tokenize(source),
+            line: 1,
+            rom: Vec::new(),
+            here: PROGRAM_MEMORY_START as u16,
+            labels: BTreeMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+            entry_pending: true,
+        }
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, AssembleError> {
+        Err(AssembleError { line: self.line, message: message.into() })
+    }
+
+    fn run(&mut self) -> Result<(), AssembleError> {
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+        self.enter()?;
+        if let Some(block) = self.blocks.last() {
+            let open = match block {
+                Block::If { .. } | Block::Else { .. } => "begin without end",
+                Block::Loop { .. } => "loop without again",
+            };
+            return self.error(open);
+        }
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            self.line = fixup.line;
+            let target = match self.labels.get(&fixup.label) {
+                Some(&target) => target,
+                None => return self.error(format!("undefined label '{}'", fixup.label)),
+            };
+            if target > fixup.max {
+                return self.error(format!("address {:#X} of '{}' is out of range", target, fixup.label));
+            }
+            let mut address = fixup.address;
+            for instruction in (fixup.make)(target) {
+                address = self.write(address, &instruction)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Execution starts at 0x200. Unless `main` is the first thing placed
+    // there, the program opens with a jump to it.
+    fn enter(&mut self) -> Result<(), AssembleError> {
+        if self.entry_pending {
+            self.entry_pending = false;
+            self.emit_to_label("main", 0xFFF, |address| vec![Instruction::Jump { address }])?;
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<String, AssembleError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.line = token.line;
+                Ok(token.text)
+            },
+            None => self.error("unexpected end of source"),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), AssembleError> {
+        let token = self.next()?;
+        if token != expected {
+            return self.error(format!("expected '{}', found '{}'", expected, token));
+        }
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, AssembleError> {
+        let name = self.next()?;
+        if parse_number(&name).is_some() || parse_register(&name).is_some() || name.starts_with(':') {
+            return self.error(format!("'{}' can't be used as a name", name));
+        }
+        Ok(name)
+    }
+
+    fn register(&mut self) -> Result<u8, AssembleError> {
+        let token = self.next()?;
+        self.register_named(&token)
+    }
+
+    fn register_named(&self, token: &str) -> Result<u8, AssembleError> {
+        match parse_register(token).or_else(|| self.aliases.get(token).copied()) {
+            Some(register) => Ok(register),
+            None => self.error(format!("expected a register, found '{}'", token)),
+        }
+    }
+
+    fn is_register(&self, token: &str) -> bool {
+        parse_register(token).is_some() || self.aliases.contains_key(token)
+    }
+
+    // A number, constant or already defined label.
+    fn lookup(&self, token: &str) -> Option<i64> {
+        parse_number(token)
+            .or_else(|| self.constants.get(token).copied())
+            .or_else(|| self.labels.get(token).map(|&address| address as i64))
+    }
+
+    fn value(&mut self) -> Result<i64, AssembleError> {
+        if self.peek() == Some("{") {
+            self.next()?;
+            return self.expression_block();
+        }
+        let token = self.next()?;
+        match self.lookup(&token) {
+            Some(value) => Ok(value),
+            None => self.error(format!("undefined name '{}'", token)),
+        }
+    }
+
+    fn bounded(&mut self, min: i64, max: i64, what: &str) -> Result<i64, AssembleError> {
+        let value = self.value()?;
+        if value < min || value > max {
+            return self.error(format!("{} {} is out of range", what, value));
+        }
+        Ok(value)
+    }
+
+    fn byte(&mut self) -> Result<u8, AssembleError> {
+        Ok(self.bounded(-128, 255, "byte")? as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, AssembleError> {
+        Ok(self.bounded(0, 15, "nibble")? as u8)
+    }
+
+    fn operand(&mut self) -> Result<Operand, AssembleError> {
+        match self.peek() {
+            Some(token) if self.is_register(token) => Ok(Operand::Register(self.register()?)),
+            _ => Ok(Operand::Value(self.byte()? as i64)),
+        }
+    }
+
+    fn write(&mut self, address: u16, instruction: &Instruction) -> Result<u16, AssembleError> {
+        let bytes = instruction.to_bytes().expect("the assembler only builds real instructions");
+        self.write_bytes(address, &bytes)
+    }
+
+    fn write_bytes(&mut self, address: u16, bytes: &[u8]) -> Result<u16, AssembleError> {
+        // Output that ended exactly at 0xFFFF leaves `here` wrapped to 0.
+        let Some(start) = (address as usize).checked_sub(PROGRAM_MEMORY_START) else {
+            return self.error("program does not fit in 64 KiB");
+        };
+        let end = start + bytes.len();
+        if PROGRAM_MEMORY_START + end > 0x10000 {
+            return self.error("program does not fit in 64 KiB");
+        }
+        if self.rom.len() < end {
+            self.rom.resize(end, 0);
+        }
+        self.rom[start..end].copy_from_slice(bytes);
+        Ok((PROGRAM_MEMORY_START + end) as u16)
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> Result<(), AssembleError> {
+        self.enter()?;
+        self.here = self.write(self.here, &instruction)?;
+        Ok(())
+    }
+
+    // Emits instructions referring to `label`, patching them later if it
+    // isn't defined yet. `max` is the highest address the operand holds.
+    fn emit_to_label(&mut self, label: &str, max: u16, make: fn(u16) -> Vec<Instruction>) -> Result<(), AssembleError> {
+        self.emit_with_address(label, max, Box::new(make))
+    }
+
+    fn emit_with_address(&mut self, label: &str, max: u16, make: Box<dyn Fn(u16) -> Vec<Instruction>>) -> Result<(), AssembleError> {
+        if let Some(address) = self.lookup(label) {
+            if !(0..=max as i64).contains(&address) {
+                return self.error(format!("address {:#X} is out of range", address));
+            }
+            for instruction in make(address as u16) {
+                self.emit(instruction)?;
+            }
+            return Ok(());
+        }
+        if parse_number(label).is_some() || self.is_register(label) {
+            return self.error(format!("expected an address, found '{}'", label));
+        }
+        let placeholder = make(0);
+        self.fixups.push(Fixup { address: self.here, max, label: label.to_string(), line: self.line, make });
+        for instruction in placeholder {
+            self.emit(instruction)?;
+        }
+        Ok(())
+    }
+
+    fn address_operand(&mut self, make: fn(u16) -> Vec<Instruction>) -> Result<(), AssembleError> {
+        let target = self.next()?;
+        self.emit_to_label(&target, 0xFFF, make)
+    }
+
+    fn statement(&mut self) -> Result<(), AssembleError> {
+        let token = self.next()?;
+        match token.as_str() {
+            ":" => {
+                let name = self.name()?;
+                if name == "main" && self.here == PROGRAM_MEMORY_START as u16 {
+                    self.entry_pending = false;
+                }
+                self.enter()?;
+                if self.labels.insert(name.clone(), self.here).is_some() {
+                    return self.error(format!("label '{}' is defined twice", name));
+                }
+            },
+            ":const" => {
+                let name = self.name()?;
+                let value = self.value()?;
+                self.constants.insert(name, value);
+            },
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+            },
+            ":calc" => {
+                let name = self.name()?;
+                self.expect("{")?;
+                let value = self.expression_block()?;
+                self.constants.insert(name, value);
+            },
+            ":macro" => self.define_macro()?,
+            ":byte" => {
+                let byte = self.byte()?;
+                self.enter()?;
+                self.here = self.write_bytes(self.here, &[byte])?;
+            },
+            ":org" => {
+                let origin = self.bounded(PROGRAM_MEMORY_START as i64, 0xFFFF, "origin")? as u16;
+                self.enter()?;
+                self.here = origin;
+            },
+            ":call" => self.address_operand(|address| vec![Instruction::CallSubroutine { address }])?,
+            ":unpack" => {
+                let high = self.nibble()? as u16;
+                let label = self.next()?;
+                let (v0, v1) = (self.aliases.get("unpack-hi").copied().unwrap_or(0), self.aliases.get("unpack-lo").copied().unwrap_or(1));
+                self.emit_with_address(&label, 0xFFF, Box::new(move |address| {
+                    vec![
+                        Instruction::LoadByteIntoRegister { register: v0, byte: (high << 4 | address >> 8) as u8 },
+                        Instruction::LoadByteIntoRegister { register: v1, byte: address as u8 },
+                    ]
+                }))?;
+            },
+            ":breakpoint" | ":proto" => {
+                self.next()?;
+            },
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            },
+            "clear" => self.emit(Instruction::ClearScreen)?,
+            "return" | ";" => self.emit(Instruction::ReturnFromSubroutine)?,
+            "exit" => self.emit(Instruction::Exit)?,
+            "lores" => self.emit(Instruction::LowResolution)?,
+            "hires" => self.emit(Instruction::HighResolution)?,
+            "scroll-left" => self.emit(Instruction::ScrollLeft)?,
+            "scroll-right" => self.emit(Instruction::ScrollRight)?,
+            "scroll-down" => {
+                let nibble = self.nibble()?;
+                self.emit(Instruction::ScrollDown { nibble })?;
+            },
+            "scroll-up" => {
+                let nibble = self.nibble()?;
+                self.emit(Instruction::ScrollUp { nibble })?;
+            },
+            "audio" => self.emit(Instruction::LoadAudioPattern)?,
+            "plane" => {
+                let mask = self.nibble()?;
+                self.emit(Instruction::SelectPlanes { mask })?;
+            },
+            "sprite" => {
+                let register1 = self.register()?;
+                let register2 = self.register()?;
+                let nibble = self.nibble()?;
+                self.emit(Instruction::DrawSprite { register1, register2, nibble })?;
+            },
+            "jump" => self.address_operand(|address| vec![Instruction::Jump { address }])?,
+            "jump0" => self.address_operand(|address| vec![Instruction::JumpToAddressPlusV0 { address }])?,
+            "bcd" => {
+                let register = self.register()?;
+                self.emit(Instruction::LoadBinaryCodedDecimalIntoMemory { register })?;
+            },
+            "save" | "load" => {
+                let register1 = self.register()?;
+                let instruction = if self.peek() == Some("-") {
+                    self.next()?;
+                    let register2 = self.register()?;
+                    if token == "save" {
+                        Instruction::SaveRegisterRange { register1, register2 }
+                    } else {
+                        Instruction::LoadRegisterRange { register1, register2 }
+                    }
+                } else if token == "save" {
+                    Instruction::LoadRegistersIntoMemory { register: register1 }
+                } else {
+                    Instruction::LoadMemoryIntoRegisters { register: register1 }
+                };
+                self.emit(instruction)?;
+            },
+            "saveflags" => {
+                let register = self.register()?;
+                self.emit(Instruction::StoreFlags { register })?;
+            },
+            "loadflags" => {
+                let register = self.register()?;
+                self.emit(Instruction::LoadFlags { register })?;
+            },
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let register = self.register()?;
+                self.emit(match token.as_str() {
+                    "delay" => Instruction::LoadRegisterIntoDelayTimer { register },
+                    "buzzer" => Instruction::LoadRegisterIntoSoundTimer { register },
+                    _ => Instruction::LoadRegisterIntoPitch { register },
+                })?;
+            },
+            "i" => self.index_statement()?,
+            "if" => self.if_statement()?,
+            "else" => match self.blocks.pop() {
+                Some(Block::If { jump }) => {
+                    let end_jump = self.here;
+                    self.emit(Instruction::Jump { address: 0 })?;
+                    self.patch_jump(jump, self.here)?;
+                    self.blocks.push(Block::Else { jump: end_jump });
+                },
+                _ => return self.error("else without if ... begin"),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If { jump }) | Some(Block::Else { jump }) => self.patch_jump(jump, self.here)?,
+                _ => return self.error("end without if ... begin"),
+            },
+            "loop" => self.blocks.push(Block::Loop { start: self.here, exits: Vec::new() }),
+            "while" => {
+                let skip_when_false = self.condition()?;
+                self.emit(negate(skip_when_false))?;
+                let exit = self.here;
+                self.emit(Instruction::Jump { address: 0 })?;
+                match self.blocks.iter_mut().rev().find(|block| matches!(block, Block::Loop { .. })) {
+                    Some(Block::Loop { exits, .. }) => exits.push(exit),
+                    _ => return self.error("while outside of a loop"),
+                }
+            },
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, exits }) => {
+                    self.emit(Instruction::Jump { address: start })?;
+                    for exit in exits {
+                        self.patch_jump(exit, self.here)?;
+                    }
+                },
+                _ => return self.error("again without loop"),
+            },
+            _ if self.is_register(&token) => self.register_statement(&token)?,
+            _ if self.macros.contains_key(&token) => self.expand_macro(&token)?,
+            _ => {
+                if let Some(value) = parse_number(&token).or_else(|| self.constants.get(&token).copied()) {
+                    // Bare numbers are data.
+                    if !(-128..=255).contains(&value) {
+                        return self.error(format!("byte {} is out of range", value));
+                    }
+                    self.enter()?;
+                    self.here = self.write_bytes(self.here, &[value as u8])?;
+                } else if token.starts_with(':') || token.contains(['{', '}', '=']) {
+                    return self.error(format!("unexpected '{}'", token));
+                } else {
+                    // Any other name is a subroutine call.
+                    self.emit_to_label(&token, 0xFFF, |address| vec![Instruction::CallSubroutine { address }])?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn patch_jump(&mut self, at: u16, target: u16) -> Result<(), AssembleError> {
+        self.write(at, &Instruction::Jump { address: target })?;
+        Ok(())
+    }
+
+    fn index_statement(&mut self) -> Result<(), AssembleError> {
+        let operator = self.next()?;
+        match operator.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    let register = self.register()?;
+                    self.emit(Instruction::LoadFontLocationIntoIndex { register })
+                },
+                Some("bighex") => {
+                    self.next()?;
+                    let register = self.register()?;
+                    self.emit(Instruction::LoadBigFontLocationIntoIndex { register })
+                },
+                Some("long") => {
+                    self.next()?;
+                    let target = self.next()?;
+                    self.emit_to_label(&target, 0xFFFF, |address| vec![Instruction::LoadLongAddressIntoIndex { address }])
+                },
+                _ => self.address_operand(|address| vec![Instruction::LoadAddressIntoIndex { address }]),
+            },
+            "+=" => {
+                let register = self.register()?;
+                self.emit(Instruction::AddRegisterToIndex { register })
+            },
+            _ => self.error(format!("unknown operator 'i {}'", operator)),
+        }
+    }
+
+    fn register_statement(&mut self, token: &str) -> Result<(), AssembleError> {
+        let register1 = self.register_named(token)?;
+        let operator = self.next()?;
+        let instruction = match (operator.as_str(), self.peek()) {
+            (":=", Some("random")) => {
+                self.next()?;
+                Instruction::RandomByteAndIntoRegister { register: register1, byte: self.byte()? }
+            },
+            (":=", Some("delay")) => {
+                self.next()?;
+                Instruction::LoadDelayTimerIntoRegister { register: register1 }
+            },
+            (":=", Some("key")) => {
+                self.next()?;
+                Instruction::WaitForKeyPress { register: register1 }
+            },
+            _ => {
+                let operand = self.operand()?;
+                match (operator.as_str(), operand) {
+                    (":=", Operand::Register(register2)) => Instruction::LoadRegisterIntoRegister { register1, register2 },
+                    (":=", Operand::Value(byte)) => Instruction::LoadByteIntoRegister { register: register1, byte: byte as u8 },
+                    ("+=", Operand::Register(register2)) => Instruction::AddRegisters { register1, register2 },
+                    ("+=", Operand::Value(byte)) => Instruction::AddByteToRegister { register: register1, byte: byte as u8 },
+                    ("-=", Operand::Register(register2)) => Instruction::SubRegisters { register1, register2 },
+                    ("-=", Operand::Value(byte)) => Instruction::AddByteToRegister { register: register1, byte: (byte as u8).wrapping_neg() },
+                    ("=-", Operand::Register(register2)) => Instruction::SubNRegisters { register1, register2 },
+                    ("|=", Operand::Register(register2)) => Instruction::OrRegisters { register1, register2 },
+                    ("&=", Operand::Register(register2)) => Instruction::AndRegisters { register1, register2 },
+                    ("^=", Operand::Register(register2)) => Instruction::XorRegisters { register1, register2 },
+                    (">>=", Operand::Register(register2)) => Instruction::ShiftRight { register1, register2 },
+                    ("<<=", Operand::Register(register2)) => Instruction::ShiftLeft { register1, register2 },
+                    _ => return self.error(format!("unknown operator '{} {}'", token, operator)),
+                }
+            },
+        };
+        self.emit(instruction)
+    }
+
+    // Emits whatever a condition needs up front and returns the skip that
+    // jumps over the next instruction when the condition is false.
+    // Comparisons go through VF like they do in Octo.
+    fn condition(&mut self) -> Result<Instruction, AssembleError> {
+        let register = self.register()?;
+        let operator = self.next()?;
+        match operator.as_str() {
+            "key" => return Ok(Instruction::SkipIfKeyNotPressed { register }),
+            "-key" => return Ok(Instruction::SkipIfKeyPressed { register }),
+            _ => {},
+        }
+        let operand = self.operand()?;
+        let skip = match (operator.as_str(), operand) {
+            ("==", Operand::Value(byte)) => Instruction::SkipIfNotEqual { register, byte: byte as u8 },
+            ("!=", Operand::Value(byte)) => Instruction::SkipIfEqual { register, byte: byte as u8 },
+            ("==", Operand::Register(register2)) => Instruction::SkipIfRegisterNotEqual { register1: register, register2 },
+            ("!=", Operand::Register(register2)) => Instruction::SkipIfRegisterEqual { register1: register, register2 },
+            ("<" | ">" | "<=" | ">=", _) => {
+                self.emit(match operand {
+                    Operand::Register(register2) => Instruction::LoadRegisterIntoRegister { register1: 0xF, register2 },
+                    Operand::Value(byte) => Instruction::LoadByteIntoRegister { register: 0xF, byte: byte as u8 },
+                })?;
+                // VF - VX sets VF when operand >= VX, VX - VF when VX >= operand.
+                let (reversed, flag) = match operator.as_str() {
+                    "<" => (true, 0),
+                    ">=" => (true, 1),
+                    ">" => (false, 0),
+                    _ => (false, 1),
+                };
+                self.emit(if reversed {
+                    Instruction::SubNRegisters { register1: 0xF, register2: register }
+                } else {
+                    Instruction::SubRegisters { register1: 0xF, register2: register }
+                })?;
+                Instruction::SkipIfNotEqual { register: 0xF, byte: flag }
+            },
+            _ => return self.error(format!("unknown comparison '{}'", operator)),
+        };
+        Ok(skip)
+    }
+
+    fn if_statement(&mut self) -> Result<(), AssembleError> {
+        let skip_when_false = self.condition()?;
+        match self.next()?.as_str() {
+            "then" => {
+                self.emit(skip_when_false)?;
+                self.statement()
+            },
+            "begin" => {
+                self.emit(negate(skip_when_false))?;
+                let jump = self.here;
+                self.emit(Instruction::Jump { address: 0 })?;
+                self.blocks.push(Block::If { jump });
+                Ok(())
+            },
+            other => self.error(format!("expected 'then' or 'begin', found '{}'", other)),
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), AssembleError> {
+        let name = self.name()?;
+        let mut parameters = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token == "{" {
+                break;
+            }
+            parameters.push(token);
+        }
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = match self.tokens.pop_front() {
+                Some(token) => token,
+                None => return self.error(format!("macro '{}' is missing its closing '}}'", name)),
+            };
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => {},
+            }
+            if depth == 0 {
+                break;
+            }
+            body.push(token);
+        }
+        self.macros.insert(name, Macro { parameters, body });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), AssembleError> {
+        let count = self.macros[name].parameters.len();
+        let mut arguments = Vec::new();
+        for _ in 0..count {
+            arguments.push(self.next()?);
+        }
+        let line = self.line;
+        let definition = &self.macros[name];
+        let expansion: Vec<Token> = definition
+            .body
+            .iter()
+            .map(|token| {
+                let text = match definition.parameters.iter().position(|parameter| *parameter == token.text) {
+                    Some(index) => arguments[index].clone(),
+                    None => token.text.clone(),
+                };
+                // Errors inside a macro point at where it was used.
+                Token { text, line }
+            })
+            .collect();
+        for token in expansion.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    // Evaluates tokens up to the closing '}'.
+    fn expression_block(&mut self) -> Result<i64, AssembleError> {
+        let mut expression = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.next()?;
+            match token.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => {},
+            }
+            if depth == 0 {
+                break;
+            }
+            expression.push(token);
+        }
+        let mut tokens = expression.iter().map(String::as_str).peekable();
+        let value = self.expression(&mut tokens)?;
+        if let Some(extra) = tokens.next() {
+            return self.error(format!("unexpected '{}' in expression", extra));
+        }
+        Ok(value)
+    }
+
+    fn expression<'a>(&self, tokens: &mut std::iter::Peekable<impl Iterator<Item = &'a str>>) -> Result<i64, AssembleError> {
+        let left = self.term(tokens)?;
+        let operator = match tokens.peek() {
+            Some(&operator) if operator != ")" => operator,
+            _ => return Ok(left),
+        };
+        tokens.next();
+        let right = self.expression(tokens)?;
+        let checked = |value: Option<i64>| value.map_or_else(|| self.error("division by zero or overflow in expression"), Ok);
+        Ok(match operator {
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" => checked(left.checked_div(right))?,
+            "%" => checked(left.checked_rem(right))?,
+            "&" => left & right,
+            "|" => left | right,
+            "^" => left ^ right,
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "<" => (left < right) as i64,
+            ">" => (left > right) as i64,
+            "<=" => (left <= right) as i64,
+            ">=" => (left >= right) as i64,
+            "==" => (left == right) as i64,
+            "!=" => (left != right) as i64,
+            "min" => left.min(right),
+            "max" => left.max(right),
+            _ => return self.error(format!("unknown operator '{}' in expression", operator)),
+        })
+    }
+
+    fn term<'a>(&self, tokens: &mut std::iter::Peekable<impl Iterator<Item = &'a str>>) -> Result<i64, AssembleError> {
+        let token = match tokens.next() {
+            Some(token) => token,
+            None => return self.error("expression ends too early"),
+        };
+        match token {
+            "(" => {
+                let value = self.expression(tokens)?;
+                if tokens.next() != Some(")") {
+                    return self.error("missing ')' in expression");
+                }
+                Ok(value)
+            },
+            "-" => Ok(self.term(tokens)?.wrapping_neg()),
+            "~" => Ok(!self.term(tokens)?),
+            "!" => Ok((self.term(tokens)? == 0) as i64),
+            "HERE" => Ok(self.here as i64),
+            _ => match self.lookup(token) {
+                Some(value) => Ok(value),
+                None => self.error(format!("undefined name '{}' in expression", token)),
+            },
+        }
+    }
+}
+
+// The skip that fires exactly when `skip` doesn't.
+fn negate(skip: Instruction) -> Instruction {
+    match skip {
+        Instruction::SkipIfEqual { register, byte } => Instruction::SkipIfNotEqual { register, byte },
+        Instruction::SkipIfNotEqual { register, byte } => Instruction::SkipIfEqual { register, byte },
+        Instruction::SkipIfRegisterEqual { register1, register2 } => Instruction::SkipIfRegisterNotEqual { register1, register2 },
+        Instruction::SkipIfRegisterNotEqual { register1, register2 } => Instruction::SkipIfRegisterEqual { register1, register2 },
+        Instruction::SkipIfKeyPressed { register } => Instruction::SkipIfKeyNotPressed { register },
+        Instruction::SkipIfKeyNotPressed { register } => Instruction::SkipIfKeyPressed { register },
+        other => unreachable!("{:?} is not a skip", other),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chip8::CHIP8;
+
+    fn rom(source: &str) -> Vec<u8> {
+        assemble(source).unwrap().rom
+    }
+
+    fn run(source: &str, steps: usize) -> CHIP8 {
+        let mut chip = CHIP8::default();
+        chip.load_from_slice(&rom(source), None).unwrap();
+        for _ in 0..steps {
+            chip.step().unwrap();
+        }
+        chip
+    }
+
+    #[test]
+    fn test_statements() {
+        let source = "
+            : main
+            clear
+            v3 := 0x1F  v3 += 1  v3 -= 2  v3 := v4  v3 += v4  v3 -= v4  v3 =- v4
+            v3 |= v4  v3 &= v4  v3 ^= v4  v3 >>= v4  v3 <<= v4
+            v1 := random 0xF0  v1 := delay  v1 := key  delay := v1  buzzer := v1
+            i := 0x123  i += v2  i := hex v2  i := bighex v2  bcd v2
+            save v5  load v5  save v1 - v3  load v3 - v1  saveflags v7  loadflags v7
+            sprite v0 v1 5  scroll-down 3  scroll-up 2  scroll-left  scroll-right
+            lores hires plane 3 audio pitch := v2 jump0 0x300 exit return
+        ";
+        let expected: Vec<u16> = vec![
+            0x00E0, 0x631F, 0x7301, 0x73FE, 0x8340, 0x8344, 0x8345, 0x8347, 0x8341, 0x8342, 0x8343, 0x8346, 0x834E,
+            0xC1F0, 0xF107, 0xF10A, 0xF115, 0xF118, 0xA123, 0xF21E, 0xF229, 0xF230, 0xF233, 0xF555, 0xF565, 0x5132,
+            0x5313, 0xF775, 0xF785, 0xD015, 0x00C3, 0x00D2, 0x00FC, 0x00FB, 0x00FE, 0x00FF, 0xF301, 0xF002, 0xF23A,
+            0xB300, 0x00FD, 0x00EE,
+        ];
+        let words: Vec<u16> = rom(source).chunks(2).map(|word| u16::from_be_bytes([word[0], word[1]])).collect();
+        assert_eq!(words, expected);
+    }
+
+    #[test]
+    fn test_labels_and_entry_point() {
+        // main isn't first, so 0x200 jumps to it.
+        let assembly = assemble(": sub  v0 := 1  ;  : main  sub  jump main  : data  1 2 0xFF").unwrap();
+        assert_eq!(
+            assembly.rom,
+            [0x12, 0x06, 0x60, 0x01, 0x00, 0xEE, 0x22, 0x02, 0x12, 0x06, 0x01, 0x02, 0xFF]
+        );
+        assert_eq!(assembly.symbol_file(), "0x0202 sub\n0x0206 main\n0x020A data\n");
+
+        // Forward references get patched once the label shows up.
+        assert_eq!(rom(": main  i := sprite  jump done  : sprite  0x3C  : done  exit"),
+                   [0xA2, 0x04, 0x12, 0x05, 0x3C, 0x00, 0xFD]);
+        assert_eq!(rom(": main  i := long far  :org 0x1000  : far  1"), {
+            let mut expected = vec![0xF0, 0x00, 0x10, 0x00];
+            expected.resize(0x1000 - 0x200, 0);
+            expected.push(1);
+            expected
+        });
+    }
+
+    #[test]
+    fn test_constants_aliases_and_calc() {
+        let source = "
+            :const speed 3
+            :alias px v4
+            :calc size { speed * 2 + 1 }
+            :calc masked { 0xFF & ~ 0x0F }
+            :calc ordered { 10 - 4 - 3 }
+            : main
+            px := speed  px += size  px := masked  px := ordered  :byte { HERE - 0x200 }
+        ";
+        // Right to left: 10 - (4 - 3) = 9, and speed * (2 + 1) = 9.
+        assert_eq!(rom(source), [0x64, 0x03, 0x74, 0x09, 0x64, 0xF0, 0x64, 0x09, 0x08]);
+    }
+
+    #[test]
+    fn test_macros() {
+        let source = "
+            :macro swap a b { vf := a  a := b  b := vf }
+            :macro twice body { body body }
+            : main
+            swap v1 v2
+            twice clear
+        ";
+        assert_eq!(rom(source), [0x8F, 0x10, 0x81, 0x20, 0x82, 0xF0, 0x00, 0xE0, 0x00, 0xE0]);
+    }
+
+    #[test]
+    fn test_control_flow() {
+        // Counts v0 to 10, adding the odd values to v1 and the even ones to v2.
+        let source = "
+            : main
+            loop
+                while v0 != 10
+                v3 := 1
+                v3 &= v0
+                if v3 == 1 begin
+                    v1 += v0
+                else
+                    v2 += v0
+                end
+                v0 += 1
+            again
+            if v1 > v2 then v4 := 1
+            if v1 < v2 then v4 := 2
+            if v1 >= 25 then v5 := 1
+            if v1 <= 24 then v5 := 2
+            : halt jump halt
+        ";
+        let chip = run(source, 500);
+        let v = chip.registers();
+        assert_eq!((v[0], v[1], v[2], v[4], v[5]), (10, 25, 20, 1, 1));
+    }
+
+    #[test]
+    fn test_unpack() {
+        assert_eq!(rom(": main  :unpack 0xA data  : data"), [0x60, 0xA2, 0x61, 0x04]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source: &str| assemble(source).unwrap_err();
+        assert_eq!(error(": main\n\n  v0 := 300").line, 3);
+        assert_eq!(error(": main  jump nowhere").message, "undefined label 'nowhere'");
+        assert_eq!(error("clear").message, "undefined label 'main'");
+        assert_eq!(error(": main  loop clear").message, "loop without again");
+        assert_eq!(error(": main  v0 |= 3").message, "unknown operator 'v0 |='");
+        assert_eq!(error(": main  jump far  :org 0x1000  : far").message, "address 0x1000 of 'far' is out of range");
+        assert_eq!(error(": main  : main").message, "label 'main' is defined twice");
+        assert_eq!(error(": main\n# comment\nelse").to_string(), "line 3: else without if ... begin");
+
+        // The last two bytes of memory fill up; a third has nowhere to go.
+        assert_eq!(assemble(": main  :org 0xFFFE  1 2").unwrap().rom.len(), 0xFE00);
+        assert_eq!(error(": main  :org 0xFFFE  1 2 3").message, "program does not fit in 64 KiB");
+    }
+}
@@ -0,0 +1,171 @@
+// The CHIP-8 only has a buzzer: it sounds while the sound timer is above
+// zero. Frontends get told once per frame whether it should be on, and
+// decide what that means.
+pub trait AudioSink {
+    fn set_tone(&mut self, active: bool);
+}
+
+// Discards everything, for headless runs.
+#[derive(Debug, Default)]
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn set_tone(&mut self, _active: bool) {}
+}
+
+// Keeps the tone state of every frame, for tests.
+#[derive(Debug, Default)]
+pub struct RecordingSink {
+    pub frames: Vec<bool>,
+}
+
+impl AudioSink for RecordingSink {
+    fn set_tone(&mut self, active: bool) {
+        self.frames.push(active);
+    }
+}
+
+pub const DEFAULT_BEEP_FREQUENCY: f32 = 440.0;
+pub const DEFAULT_VOLUME: f32 = 0.25;
+const RAMP_SECONDS: f32 = 0.005;
+
+// "880" for --beep: a finite frequency above 0 Hz.
+pub fn parse_frequency(s: &str) -> Result<f32, String> {
+    match s.trim().parse::<f32>() {
+        Ok(frequency) if frequency.is_finite() && frequency > 0.0 => Ok(frequency),
+        _ => Err(format!("invalid frequency '{}', expected a number of Hz above 0", s)),
+    }
+}
+
+// Square wave generator for audio callbacks. Starting and stopping fades
+// the amplitude over a few milliseconds instead of jumping straight to
+// full volume or silence, which is what makes a click.
+#[derive(Debug, Clone)]
+pub struct SquareWave {
+    phase: f32,
+    phase_step: f32,
+    volume: f32,
+    envelope: f32,
+    envelope_step: f32,
+    active: bool,
+}
+
+impl SquareWave {
+    // Frequencies above half the sample rate would alias, so they are
+    // clamped to it. Anything that isn't a positive number plays the
+    // default pitch.
+    pub fn new(sample_rate: u32, frequency: f32, volume: f32) -> SquareWave {
+        let frequency = if frequency > 0.0 { frequency } else { DEFAULT_BEEP_FREQUENCY };
+        SquareWave {
+            phase: 0.0,
+            phase_step: frequency.min(sample_rate as f32 / 2.0) / sample_rate as f32,
+            volume: volume.clamp(0.0, 1.0),
+            envelope: 0.0,
+            envelope_step: 1.0 / (RAMP_SECONDS * sample_rate as f32),
+            active: false,
+        }
+    }
+
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+    }
+
+    pub fn fill(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            let target = if self.active { 1.0 } else { 0.0 };
+            if self.envelope < target {
+                self.envelope = (self.envelope + self.envelope_step).min(target);
+            } else if self.envelope > target {
+                self.envelope = (self.envelope - self.envelope_step).max(target);
+            }
+
+            if self.envelope == 0.0 {
+                // Restart in phase so every beep begins the same way.
+                self.phase = 0.0;
+                *sample = 0.0;
+                continue;
+            }
+            let level = if self.phase < 0.5 { 1.0 } else { -1.0 };
+            *sample = level * self.volume * self.envelope;
+            self.phase = (self.phase + self.phase_step) % 1.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44_100;
+
+    #[test]
+    fn test_silent_until_active() {
+        let mut wave = SquareWave::new(SAMPLE_RATE, 440.0, 0.5);
+        let mut buffer = [1.0f32; 512];
+        wave.fill(&mut buffer);
+        assert!(buffer.iter().all(|&s| s == 0.0));
+
+        wave.set_active(true);
+        wave.fill(&mut buffer);
+        assert!(buffer.iter().any(|&s| s.abs() == 0.5));
+        assert!(buffer.iter().all(|&s| s.abs() <= 0.5));
+    }
+
+    #[test]
+    fn test_no_clicks_on_start_and_stop() {
+        let mut wave = SquareWave::new(SAMPLE_RATE, 440.0, 1.0);
+        let mut buffer = [0.0f32; 4096];
+        let max_step = 1.0 / (RAMP_SECONDS * SAMPLE_RATE as f32) + f32::EPSILON;
+
+        wave.set_active(true);
+        wave.fill(&mut buffer);
+        assert!(buffer[0].abs() <= max_step, "tone started at {}", buffer[0]);
+
+        wave.set_active(false);
+        let mut tail = [0.0f32; 4096];
+        wave.fill(&mut tail);
+        // Once stopped the magnitude only ever shrinks, by at most one ramp step.
+        let mut previous = buffer[buffer.len() - 1].abs();
+        for &sample in tail.iter() {
+            assert!(sample.abs() <= previous + f32::EPSILON);
+            assert!(previous - sample.abs() <= max_step);
+            previous = sample.abs();
+        }
+        assert_eq!(tail[tail.len() - 1], 0.0);
+    }
+
+    #[test]
+    fn test_frequency() {
+        let mut wave = SquareWave::new(SAMPLE_RATE, 441.0, 1.0);
+        wave.set_active(true);
+        wave.fill(&mut [0.0f32; 1000]);
+        // Count rising edges over one second, once the ramp is over.
+        let mut buffer = vec![0.0f32; SAMPLE_RATE as usize];
+        wave.fill(&mut buffer);
+        let edges = buffer.windows(2).filter(|w| w[0] < 0.0 && w[1] > 0.0).count();
+        assert!((438..=441).contains(&edges), "{} edges", edges);
+    }
+
+    #[test]
+    fn test_frequency_limits() {
+        let step = |frequency: f32| SquareWave::new(SAMPLE_RATE, frequency, 1.0).phase_step;
+        assert_eq!(step(100_000.0), 0.5);
+        assert_eq!(step(f32::INFINITY), 0.5);
+        for frequency in [0.0, -440.0, f32::NAN] {
+            assert_eq!(step(frequency), DEFAULT_BEEP_FREQUENCY / SAMPLE_RATE as f32);
+        }
+
+        assert_eq!(parse_frequency("880"), Ok(880.0));
+        for s in ["0", "-1", "NaN", "inf", "loud"] {
+            assert!(parse_frequency(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_recording_sink() {
+        let mut sink = RecordingSink::default();
+        sink.set_tone(false);
+        sink.set_tone(true);
+        assert_eq!(sink.frames, [false, true]);
+    }
+}
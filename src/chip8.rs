@@ -1,17 +1,28 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
-use crate::types::Registers;
+use crate::display::{Display, PLANE_COUNT};
+use crate::error::{EmulationError, Fault};
+use crate::quirks::{IndexIncrement, KeyWaitMode, Platform, Quirks};
+use crate::rng::Rng;
+use crate::savestate::{StateError, StateReader, StateWriter};
+use crate::types::{Keypad, Registers};
 use std::default::Default;
-use std::fs::read;
+use std::fmt;
+use std::fs::{read, write};
+use std::io;
 use std::path::Path;
-use rand::Rng;
-use sdl2::keyboard::Scancode;
-use sdl2::rect::Point;
 pub const MEMORY_SIZE: usize = 4 * 1024; // 0x1000 directions, from 0x0 to 0xFFF.
-pub const DISPLAY_WIDTH: usize = 64;
-pub const DISPLAY_HEIGHT: usize = 32;
+pub const XO_CHIP_MEMORY_SIZE: usize = 64 * 1024; // XO-CHIP addresses the full 16 bits.
+pub use crate::display::{DISPLAY_HEIGHT, DISPLAY_WIDTH, HIRES_DISPLAY_HEIGHT, HIRES_DISPLAY_WIDTH};
 pub const REGISTER_SIZE: usize = 16;
+pub const FLAG_REGISTER_SIZE: usize = 16; // SUPER-CHIP "RPL" user flags.
+pub const STACK_SIZE: usize = 16;
+pub const KEYPAD_SIZE: usize = 16;
 pub const PROGRAM_MEMORY_START: usize = 0x200; // Programs usually start a 0x200.
+pub const FONT_ADDRESS: usize = 0x50;
+pub const BIG_FONT_ADDRESS: usize = 0xA0; // Right after the small font.
+pub const AUDIO_PATTERN_SIZE: usize = 16; // XO-CHIP 128 bit sample buffer.
+pub const DEFAULT_PITCH: u8 = 64; // Plays the audio pattern at 4000 Hz.
 
 #[inline]
 fn high_nibble(b: u8) -> u8 {
@@ -43,7 +54,7 @@ fn address_from_nibbles(a: u8, b: u8, c: u8) -> u16 {
     ((a as u16) << 8) + ((b as u16) << 4) + (c as u16)
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Instruction {
     NoOperation,
     ClearScreen,
@@ -61,9 +72,9 @@ pub enum Instruction {
     XorRegisters { register1: u8, register2: u8 },
     AddRegisters { register1: u8, register2: u8 },
     SubRegisters { register1: u8, register2: u8 },
-    ShiftRight { register: u8 },
+    ShiftRight { register1: u8, register2: u8 },
     SubNRegisters { register1: u8, register2: u8 },
-    ShiftLeft { register: u8, },
+    ShiftLeft { register1: u8, register2: u8 },
     SkipIfRegisterNotEqual { register1: u8, register2: u8 },
     LoadAddressIntoIndex { address: u16 },
     JumpToAddressPlusV0 { address: u16 },
@@ -80,41 +91,304 @@ pub enum Instruction {
     LoadBinaryCodedDecimalIntoMemory { register: u8 },
     LoadRegistersIntoMemory { register: u8 },
     LoadMemoryIntoRegisters { register: u8 },
+    ScrollDown { nibble: u8 },
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    LowResolution,
+    HighResolution,
+    LoadBigFontLocationIntoIndex { register: u8 },
+    StoreFlags { register: u8 },
+    LoadFlags { register: u8 },
+    ScrollUp { nibble: u8 },
+    SaveRegisterRange { register1: u8, register2: u8 },
+    LoadRegisterRange { register1: u8, register2: u8 },
+    LoadLongAddressIntoIndex { address: u16 },
+    SelectPlanes { mask: u8 },
+    LoadAudioPattern,
+    LoadRegisterIntoPitch { register: u8 },
     UnknownInstruction,
 }
 
+impl Instruction {
+    // Decodes the instruction at the start of `bytes`, or returns `None` if
+    // it runs past the end. Opcodes that mean nothing decode as
+    // `UnknownInstruction`.
+    pub fn decode(bytes: &[u8]) -> Option<Instruction> {
+        let (high, low) = match bytes {
+            [high, low, ..] => (*high, *low),
+            _ => return None,
+        };
+        let [first_nibble, second_nibble] = low_and_high_nibbles(high);
+        let [third_nibble, fourth_nibble] = low_and_high_nibbles(low);
+        let hex = [first_nibble, second_nibble, third_nibble, fourth_nibble];
+
+        let instruction = match hex {
+            [0x0, 0x0, 0xC, n] => Instruction::ScrollDown { nibble: n },
+            [0x0, 0x0, 0xD, n] => Instruction::ScrollUp { nibble: n },
+            [0x0, 0x0, 0xE, 0x0] => Instruction::ClearScreen,
+            [0x0, 0x0, 0xE, 0xE] => Instruction::ReturnFromSubroutine,
+            [0x0, 0x0, 0xF, 0xB] => Instruction::ScrollRight,
+            [0x0, 0x0, 0xF, 0xC] => Instruction::ScrollLeft,
+            [0x0, 0x0, 0xF, 0xD] => Instruction::Exit,
+            [0x0, 0x0, 0xF, 0xE] => Instruction::LowResolution,
+            [0x0, 0x0, 0xF, 0xF] => Instruction::HighResolution,
+            [0x1, n1, n2, n3] => Instruction::Jump { address: from_nibbles(0x0, n1, n2, n3) },
+            [0x2, n1, n2, n3] => Instruction::CallSubroutine { address: from_nibbles(0x0, n1, n2, n3) },
+            [0x3, x, n1, n2] => Instruction::SkipIfEqual { register: x, byte: from_low_and_high(n1, n2) },
+            [0x4, x, n1, n2] => Instruction::SkipIfNotEqual { register: x, byte: from_low_and_high(n1, n2) },
+            [0x5, x, y, 0x0] => Instruction::SkipIfRegisterEqual { register1: x, register2: y },
+            [0x5, x, y, 0x2] => Instruction::SaveRegisterRange { register1: x, register2: y },
+            [0x5, x, y, 0x3] => Instruction::LoadRegisterRange { register1: x, register2: y },
+            [0x6, x, n1, n2] => Instruction::LoadByteIntoRegister { register: x, byte: from_low_and_high(n1, n2) },
+            [0x7, x, n1, n2] => Instruction::AddByteToRegister { register: x, byte: from_low_and_high(n1, n2) },
+            [0x8, x, y, 0x0] => Instruction::LoadRegisterIntoRegister { register1: x, register2: y },
+            [0x8, x, y, 0x1] => Instruction::OrRegisters { register1: x, register2: y },
+            [0x8, x, y, 0x2] => Instruction::AndRegisters { register1: x, register2: y },
+            [0x8, x, y, 0x3] => Instruction::XorRegisters { register1: x, register2: y },
+            [0x8, x, y, 0x4] => Instruction::AddRegisters { register1: x, register2: y },
+            [0x8, x, y, 0x5] => Instruction::SubRegisters { register1: x, register2: y },
+            [0x8, x, y, 0x6] => Instruction::ShiftRight { register1: x, register2: y },
+            [0x8, x, y, 0x7] => Instruction::SubNRegisters { register1: x, register2: y },
+            [0x8, x, y, 0xE] => Instruction::ShiftLeft { register1: x, register2: y },
+            [0x9, x, y, 0x0] => Instruction::SkipIfRegisterNotEqual { register1: x, register2: y },
+            [0xA, n1, n2, n3] => Instruction::LoadAddressIntoIndex { address: address_from_nibbles(n1, n2, n3) },
+            [0xB, n1, n2, n3] => Instruction::JumpToAddressPlusV0 { address: address_from_nibbles(n1, n2, n3) },
+            [0xC, x, n2, n3] => Instruction::RandomByteAndIntoRegister { register: x, byte: from_low_and_high(n2, n3) },
+            [0xD, x, y, n] => Instruction::DrawSprite { register1: x, register2: y, nibble: n },
+            [0xE, x, 0x9, 0xE] => Instruction::SkipIfKeyPressed { register: x },
+            [0xE, x, 0xA, 0x1] => Instruction::SkipIfKeyNotPressed { register: x },
+            [0xF, 0x0, 0x0, 0x0] => match bytes.get(2..4) {
+                Some(&[high, low]) => Instruction::LoadLongAddressIntoIndex { address: u16::from_be_bytes([high, low]) },
+                _ => return None,
+            },
+            [0xF, n, 0x0, 0x1] => Instruction::SelectPlanes { mask: n },
+            [0xF, 0x0, 0x0, 0x2] => Instruction::LoadAudioPattern,
+            [0xF, x, 0x0, 0x7] => Instruction::LoadDelayTimerIntoRegister { register: x },
+            [0xF, x, 0x0, 0xA] => Instruction::WaitForKeyPress { register: x },
+            [0xF, x, 0x1, 0x5] => Instruction::LoadRegisterIntoDelayTimer { register: x },
+            [0xF, x, 0x1, 0x8] => Instruction::LoadRegisterIntoSoundTimer { register: x },
+            [0xF, x, 0x1, 0xE] => Instruction::AddRegisterToIndex { register: x },
+            [0xF, x, 0x2, 0x9] => Instruction::LoadFontLocationIntoIndex { register: x },
+            [0xF, x, 0x3, 0x0] => Instruction::LoadBigFontLocationIntoIndex { register: x },
+            [0xF, x, 0x3, 0x3] => Instruction::LoadBinaryCodedDecimalIntoMemory { register: x },
+            [0xF, x, 0x3, 0xA] => Instruction::LoadRegisterIntoPitch { register: x },
+            [0xF, x, 0x5, 0x5] => Instruction::LoadRegistersIntoMemory { register: x },
+            [0xF, x, 0x6, 0x5] => Instruction::LoadMemoryIntoRegisters { register: x },
+            [0xF, x, 0x7, 0x5] => Instruction::StoreFlags { register: x },
+            [0xF, x, 0x8, 0x5] => Instruction::LoadFlags { register: x },
+            _ => Instruction::UnknownInstruction,
+        };
+        Some(instruction)
+    }
+
+    // Inverse of `decode`: whenever this returns an opcode, decoding it
+    // gives back `self`. For F000 NNNN this is the first word, the address
+    // follows it. `NoOperation` and `UnknownInstruction` have no opcode of
+    // their own, and neither does anything with a field too wide for its
+    // nibbles, rather than having it silently truncated.
+    pub fn encode(&self) -> Option<u16> {
+        let nibble = |value: u8| if value <= 0xF { Some(value as u16) } else { None };
+        let xy = |op: u16, x: u8, y: u8, n: u8| Some(op << 12 | nibble(x)? << 8 | nibble(y)? << 4 | nibble(n)?);
+        let xnn = |op: u16, x: u8, nn: u8| Some(op << 12 | nibble(x)? << 8 | nn as u16);
+        let nnn = |op: u16, address: u16| if address <= 0xFFF { Some(op << 12 | address) } else { None };
+        match *self {
+            Instruction::NoOperation | Instruction::UnknownInstruction => None,
+            Instruction::ClearScreen => Some(0x00E0),
+            Instruction::ReturnFromSubroutine => Some(0x00EE),
+            Instruction::Jump { address } => nnn(0x1, address),
+            Instruction::CallSubroutine { address } => nnn(0x2, address),
+            Instruction::SkipIfEqual { register, byte } => xnn(0x3, register, byte),
+            Instruction::SkipIfNotEqual { register, byte } => xnn(0x4, register, byte),
+            Instruction::SkipIfRegisterEqual { register1, register2 } => xy(0x5, register1, register2, 0x0),
+            Instruction::LoadByteIntoRegister { register, byte } => xnn(0x6, register, byte),
+            Instruction::AddByteToRegister { register, byte } => xnn(0x7, register, byte),
+            Instruction::LoadRegisterIntoRegister { register1, register2 } => xy(0x8, register1, register2, 0x0),
+            Instruction::OrRegisters { register1, register2 } => xy(0x8, register1, register2, 0x1),
+            Instruction::AndRegisters { register1, register2 } => xy(0x8, register1, register2, 0x2),
+            Instruction::XorRegisters { register1, register2 } => xy(0x8, register1, register2, 0x3),
+            Instruction::AddRegisters { register1, register2 } => xy(0x8, register1, register2, 0x4),
+            Instruction::SubRegisters { register1, register2 } => xy(0x8, register1, register2, 0x5),
+            Instruction::ShiftRight { register1, register2 } => xy(0x8, register1, register2, 0x6),
+            Instruction::SubNRegisters { register1, register2 } => xy(0x8, register1, register2, 0x7),
+            Instruction::ShiftLeft { register1, register2 } => xy(0x8, register1, register2, 0xE),
+            Instruction::SkipIfRegisterNotEqual { register1, register2 } => xy(0x9, register1, register2, 0x0),
+            Instruction::LoadAddressIntoIndex { address } => nnn(0xA, address),
+            Instruction::JumpToAddressPlusV0 { address } => nnn(0xB, address),
+            Instruction::RandomByteAndIntoRegister { register, byte } => xnn(0xC, register, byte),
+            Instruction::DrawSprite { register1, register2, nibble } => xy(0xD, register1, register2, nibble),
+            Instruction::SkipIfKeyPressed { register } => xnn(0xE, register, 0x9E),
+            Instruction::SkipIfKeyNotPressed { register } => xnn(0xE, register, 0xA1),
+            Instruction::LoadDelayTimerIntoRegister { register } => xnn(0xF, register, 0x07),
+            Instruction::WaitForKeyPress { register } => xnn(0xF, register, 0x0A),
+            Instruction::LoadRegisterIntoDelayTimer { register } => xnn(0xF, register, 0x15),
+            Instruction::LoadRegisterIntoSoundTimer { register } => xnn(0xF, register, 0x18),
+            Instruction::AddRegisterToIndex { register } => xnn(0xF, register, 0x1E),
+            Instruction::LoadFontLocationIntoIndex { register } => xnn(0xF, register, 0x29),
+            Instruction::LoadBinaryCodedDecimalIntoMemory { register } => xnn(0xF, register, 0x33),
+            Instruction::LoadRegistersIntoMemory { register } => xnn(0xF, register, 0x55),
+            Instruction::LoadMemoryIntoRegisters { register } => xnn(0xF, register, 0x65),
+            Instruction::ScrollDown { nibble } => xy(0x0, 0x0, 0xC, nibble),
+            Instruction::ScrollRight => Some(0x00FB),
+            Instruction::ScrollLeft => Some(0x00FC),
+            Instruction::Exit => Some(0x00FD),
+            Instruction::LowResolution => Some(0x00FE),
+            Instruction::HighResolution => Some(0x00FF),
+            Instruction::LoadBigFontLocationIntoIndex { register } => xnn(0xF, register, 0x30),
+            Instruction::StoreFlags { register } => xnn(0xF, register, 0x75),
+            Instruction::LoadFlags { register } => xnn(0xF, register, 0x85),
+            Instruction::ScrollUp { nibble } => xy(0x0, 0x0, 0xD, nibble),
+            Instruction::SaveRegisterRange { register1, register2 } => xy(0x5, register1, register2, 0x2),
+            Instruction::LoadRegisterRange { register1, register2 } => xy(0x5, register1, register2, 0x3),
+            Instruction::LoadLongAddressIntoIndex { .. } => Some(0xF000),
+            Instruction::SelectPlanes { mask } => xnn(0xF, mask, 0x01),
+            Instruction::LoadAudioPattern => Some(0xF002),
+            Instruction::LoadRegisterIntoPitch { register } => xnn(0xF, register, 0x3A),
+        }
+    }
+
+    // The instruction as it sits in memory, long address included.
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        let mut bytes = self.encode()?.to_be_bytes().to_vec();
+        if let Instruction::LoadLongAddressIntoIndex { address } = self {
+            bytes.extend_from_slice(&address.to_be_bytes());
+        }
+        Some(bytes)
+    }
+
+    // Bytes taken up in memory. Only XO-CHIP's F000 NNNN is longer than two.
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LoadLongAddressIntoIndex { .. } => 4,
+            _ => 2,
+        }
+    }
+}
+
+// Mnemonics in the style of Cowgod's reference, extended with the usual
+// SUPER-CHIP and XO-CHIP names.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::NoOperation => write!(f, "NOP"),
+            Instruction::ClearScreen => write!(f, "CLS"),
+            Instruction::ReturnFromSubroutine => write!(f, "RET"),
+            Instruction::Jump { address } => write!(f, "JP {:#05X}", address),
+            Instruction::CallSubroutine { address } => write!(f, "CALL {:#05X}", address),
+            Instruction::SkipIfEqual { register, byte } => write!(f, "SE V{:X}, {:#04X}", register, byte),
+            Instruction::SkipIfNotEqual { register, byte } => write!(f, "SNE V{:X}, {:#04X}", register, byte),
+            Instruction::SkipIfRegisterEqual { register1, register2 } => write!(f, "SE V{:X}, V{:X}", register1, register2),
+            Instruction::LoadByteIntoRegister { register, byte } => write!(f, "LD V{:X}, {:#04X}", register, byte),
+            Instruction::AddByteToRegister { register, byte } => write!(f, "ADD V{:X}, {:#04X}", register, byte),
+            Instruction::LoadRegisterIntoRegister { register1, register2 } => write!(f, "LD V{:X}, V{:X}", register1, register2),
+            Instruction::OrRegisters { register1, register2 } => write!(f, "OR V{:X}, V{:X}", register1, register2),
+            Instruction::AndRegisters { register1, register2 } => write!(f, "AND V{:X}, V{:X}", register1, register2),
+            Instruction::XorRegisters { register1, register2 } => write!(f, "XOR V{:X}, V{:X}", register1, register2),
+            Instruction::AddRegisters { register1, register2 } => write!(f, "ADD V{:X}, V{:X}", register1, register2),
+            Instruction::SubRegisters { register1, register2 } => write!(f, "SUB V{:X}, V{:X}", register1, register2),
+            Instruction::ShiftRight { register1, register2 } => write!(f, "SHR V{:X}, V{:X}", register1, register2),
+            Instruction::SubNRegisters { register1, register2 } => write!(f, "SUBN V{:X}, V{:X}", register1, register2),
+            Instruction::ShiftLeft { register1, register2 } => write!(f, "SHL V{:X}, V{:X}", register1, register2),
+            Instruction::SkipIfRegisterNotEqual { register1, register2 } => write!(f, "SNE V{:X}, V{:X}", register1, register2),
+            Instruction::LoadAddressIntoIndex { address } => write!(f, "LD I, {:#05X}", address),
+            Instruction::JumpToAddressPlusV0 { address } => write!(f, "JP V0, {:#05X}", address),
+            Instruction::RandomByteAndIntoRegister { register, byte } => write!(f, "RND V{:X}, {:#04X}", register, byte),
+            Instruction::DrawSprite { register1, register2, nibble } => write!(f, "DRW V{:X}, V{:X}, {}", register1, register2, nibble),
+            Instruction::SkipIfKeyPressed { register } => write!(f, "SKP V{:X}", register),
+            Instruction::SkipIfKeyNotPressed { register } => write!(f, "SKNP V{:X}", register),
+            Instruction::LoadDelayTimerIntoRegister { register } => write!(f, "LD V{:X}, DT", register),
+            Instruction::WaitForKeyPress { register } => write!(f, "LD V{:X}, K", register),
+            Instruction::LoadRegisterIntoDelayTimer { register } => write!(f, "LD DT, V{:X}", register),
+            Instruction::LoadRegisterIntoSoundTimer { register } => write!(f, "LD ST, V{:X}", register),
+            Instruction::AddRegisterToIndex { register } => write!(f, "ADD I, V{:X}", register),
+            Instruction::LoadFontLocationIntoIndex { register } => write!(f, "LD F, V{:X}", register),
+            Instruction::LoadBinaryCodedDecimalIntoMemory { register } => write!(f, "LD B, V{:X}", register),
+            Instruction::LoadRegistersIntoMemory { register } => write!(f, "LD [I], V{:X}", register),
+            Instruction::LoadMemoryIntoRegisters { register } => write!(f, "LD V{:X}, [I]", register),
+            Instruction::ScrollDown { nibble } => write!(f, "SCD {}", nibble),
+            Instruction::ScrollRight => write!(f, "SCR"),
+            Instruction::ScrollLeft => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::LowResolution => write!(f, "LOW"),
+            Instruction::HighResolution => write!(f, "HIGH"),
+            Instruction::LoadBigFontLocationIntoIndex { register } => write!(f, "LD HF, V{:X}", register),
+            Instruction::StoreFlags { register } => write!(f, "LD R, V{:X}", register),
+            Instruction::LoadFlags { register } => write!(f, "LD V{:X}, R", register),
+            Instruction::ScrollUp { nibble } => write!(f, "SCU {}", nibble),
+            Instruction::SaveRegisterRange { register1, register2 } => write!(f, "SAVE V{:X} - V{:X}", register1, register2),
+            Instruction::LoadRegisterRange { register1, register2 } => write!(f, "LOAD V{:X} - V{:X}", register1, register2),
+            Instruction::LoadLongAddressIntoIndex { address } => write!(f, "LD I, LONG {:#06X}", address),
+            Instruction::SelectPlanes { mask } => write!(f, "PLANE {}", mask),
+            Instruction::LoadAudioPattern => write!(f, "AUDIO"),
+            Instruction::LoadRegisterIntoPitch { register } => write!(f, "PITCH V{:X}", register),
+            Instruction::UnknownInstruction => write!(f, "???"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum KeyWait {
+    Idle,
+    Waiting,
+    Pressed(u8),
+    Done(u8),
+}
+
 #[derive(Debug)]
 pub struct CHIP8 {
-    memory: [u8; MEMORY_SIZE],
-    display: [[u8; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+    memory: Vec<u8>,
+    display: Display,
     registers: Registers,
+    flags: [u8; FLAG_REGISTER_SIZE],
     stack: Vec<u16>,
     pc: u16,
     sp: u8,
     index: u16,
     delay_timer: u8,
     sound_timer: u8,
-    pressed_key: Option<u8>, // :^)
+    keypad: Keypad,
+    key_wait: KeyWait,
+    quirks: Quirks,
+    halted: bool,
+    audio_pattern: [u8; AUDIO_PATTERN_SIZE],
+    pitch: u8,
+    rng: Rng,
+    instruction_pc: u16, // Address and opcode of the last fetched instruction,
+    opcode: u16,         // reported back in faults.
 }
 
 impl Default for CHIP8 {
     fn default() -> CHIP8 {
+        CHIP8::new(Platform::CosmacVip)
+    }
+}
+
+impl CHIP8 {
+    // A blank machine with the memory size and quirks of `platform`.
+    pub fn new(platform: Platform) -> CHIP8 {
         CHIP8 {
-            memory: [0u8; MEMORY_SIZE],
-            display: [[0u8; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+            memory: vec![0u8; platform.memory_size()],
+            display: Display::default(),
             registers: Registers([0u8; REGISTER_SIZE]),
+            flags: [0u8; FLAG_REGISTER_SIZE],
             stack: Vec::new(),
             pc: PROGRAM_MEMORY_START as u16,
             sp: 0x0,
             index: 0x0,
             delay_timer: 0x0,
             sound_timer: 0x0,
-            pressed_key: None
+            keypad: Keypad::default(),
+            key_wait: KeyWait::Idle,
+            quirks: platform.quirks(),
+            halted: false,
+            audio_pattern: [0u8; AUDIO_PATTERN_SIZE],
+            pitch: DEFAULT_PITCH,
+            rng: Rng::from_entropy(),
+            instruction_pc: PROGRAM_MEMORY_START as u16,
+            opcode: 0x0,
         }
     }
-}
 
-impl CHIP8 {
     pub fn load_font(&mut self) {
         let font: [u8; 5 * 16] = [
             0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -135,133 +409,132 @@ impl CHIP8 {
             0xF0, 0x80, 0xF0, 0x80, 0x80, // F
         ];
         for (pos, e) in font.iter().enumerate() {
-            self.memory[0x50 + pos] = *e;
+            self.memory[FONT_ADDRESS + pos] = *e;
+        }
+
+        // SUPER-CHIP 8x10 digits for Fx30.
+        let big_font: [u8; 10 * 16] = [
+            0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+            0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+            0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+            0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+            0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+            0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+            0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+            0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+        ];
+        for (pos, e) in big_font.iter().enumerate() {
+            self.memory[BIG_FONT_ADDRESS + pos] = *e;
         }
     }
 
     // Load program from address, if not specified,
     // default to 0x200.
-    pub fn load_from_slice(&mut self, slice: &[u8], address: Option<u16>) {
+    pub fn load_from_slice(&mut self, slice: &[u8], address: Option<u16>) -> io::Result<()> {
         let start_address = match address {
-            Some(address) => address,
-            None => PROGRAM_MEMORY_START as u16,
+            Some(address) => address as usize,
+            None => PROGRAM_MEMORY_START,
         };
-        for (i, _) in slice.iter().enumerate() {
-            self.memory[i + start_address as usize] = slice[i];
+        match self.memory.get_mut(start_address..start_address + slice.len()) {
+            Some(memory) => memory.copy_from_slice(slice),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "ROM does not fit in memory")),
         }
+        Ok(())
     }
 
-    pub fn load_from_file(&mut self, path: &Path) {
-        let path = path.canonicalize().unwrap();
-        let file = read(path).unwrap();
-        for (i, _) in file.iter().enumerate() {
-            self.memory[i + PROGRAM_MEMORY_START] = file[i];
-        }
+    pub fn load_from_file(&mut self, path: &Path) -> io::Result<()> {
+        let file = read(path)?;
+        self.load_from_slice(&file, None)
     }
 
-    pub fn fetch(&mut self) -> Instruction {
+    pub fn fetch(&mut self) -> Result<Instruction, EmulationError> {
         let upc = self.pc as usize;
-        let [first_nibble, second_nibble] = low_and_high_nibbles(self.memory[upc]);
-        let [third_nibble, fourth_nibble] = low_and_high_nibbles(self.memory[upc + 1]);
-        let hex = [first_nibble, second_nibble, third_nibble, fourth_nibble];
+        self.instruction_pc = self.pc;
+        self.opcode = match self.memory.get(upc..upc + 2) {
+            Some(&[high, low]) => u16::from_be_bytes([high, low]),
+            _ => 0x0,
+        };
+        let instruction = match Instruction::decode(self.memory.get(upc..).unwrap_or(&[])) {
+            Some(instruction) => instruction,
+            None => return Err(self.fault(Fault::PcOutOfBounds)),
+        };
+        self.pc = self.pc.wrapping_add(instruction.size());
+        Ok(instruction)
+    }
 
-        self.pc += 2;
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
 
-        match hex {
-            [0x0, 0x0, 0xE, 0x0] => Instruction::ClearScreen,
-            [0x0, 0x0, 0xE, 0xE] => Instruction::ReturnFromSubroutine,
-            [0x1, n1, n2, n3] => Instruction::Jump { address: from_nibbles(0x0, n1, n2, n3) },
-            [0x2, n1, n2, n3] => Instruction::CallSubroutine { address: from_nibbles(0x0, n1, n2, n3) },
-            [0x3, x, n1, n2] => Instruction::SkipIfEqual { register: x, byte: from_low_and_high(n1, n2) },
-            [0x4, x, n1, n2] => Instruction::SkipIfNotEqual { register: x, byte: from_low_and_high(n1, n2) },
-            [0x5, x, y, 0x0] => Instruction::SkipIfRegisterEqual { register1: x, register2: y },
-            [0x6, x, n1, n2] => Instruction::LoadByteIntoRegister { register: x, byte: from_low_and_high(n1, n2) },
-            [0x7, x, n1, n2] => Instruction::AddByteToRegister { register: x, byte: from_low_and_high(n1, n2) },
-            [0x8, x, y, 0x0] => Instruction::LoadRegisterIntoRegister { register1: x, register2: y },
-            [0x8, x, y, 0x1] => Instruction::OrRegisters { register1: x, register2: y },
-            [0x8, x, y, 0x2] => Instruction::AndRegisters { register1: x, register2: y },
-            [0x8, x, y, 0x3] => Instruction::XorRegisters { register1: x, register2: y },
-            [0x8, x, y, 0x4] => Instruction::AddRegisters { register1: x, register2: y },
-            [0x8, x, y, 0x5] => Instruction::SubRegisters { register1: x, register2: y },
-            [0x8, x, _, 0x6] => Instruction::ShiftRight { register: x },
-            [0x8, x, y, 0x7] => Instruction::SubNRegisters { register1: x, register2: y },
-            [0x8, x, _, 0xE] => Instruction::ShiftLeft { register: x },
-            [0x9, x, y, 0x0] => Instruction::SkipIfRegisterNotEqual { register1: x, register2: y },
-            [0xA, n1, n2, n3] => Instruction::LoadAddressIntoIndex { address: address_from_nibbles(n1, n2, n3) },
-            [0xB, n1, n2, n3] => Instruction::JumpToAddressPlusV0 { address: address_from_nibbles(n1, n2, n3) },
-            [0xC, x, n2, n3] => Instruction::RandomByteAndIntoRegister { register: x, byte: from_low_and_high(n2, n3) },
-            [0xD, x, y, n] => Instruction::DrawSprite { register1: x, register2: y, nibble: n },
-            [0xE, x, 0x9, 0xE] => Instruction::SkipIfKeyPressed { register: x },
-            [0xE, x, 0xA, 0x1] => Instruction::SkipIfKeyNotPressed { register: x },
-            [0xF, x, 0x0, 0x7] => Instruction::LoadDelayTimerIntoRegister { register: x },
-            [0xF, x, 0x0, 0xA] => Instruction::WaitForKeyPress { register: x },
-            [0xF, x, 0x1, 0x5] => Instruction::LoadRegisterIntoDelayTimer { register: x },
-            [0xF, x, 0x1, 0x8] => Instruction::LoadRegisterIntoSoundTimer { register: x },
-            [0xF, x, 0x1, 0xE] => Instruction::AddRegisterToIndex { register: x },
-            [0xF, x, 0x2, 0x9] => Instruction::LoadFontLocationIntoIndex { register: x },
-            [0xF, x, 0x3, 0x3] => Instruction::LoadBinaryCodedDecimalIntoMemory { register: x },
-            [0xF, x, 0x5, 0x5] => Instruction::LoadRegistersIntoMemory { register: x },
-            [0xF, x, 0x6, 0x5] => Instruction::LoadMemoryIntoRegisters { register: x },
-            _ => Instruction::UnknownInstruction,
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    // Key edges are latched here rather than sampled by Fx0A, so a tap
+    // that starts and ends between two instructions still counts.
+    pub fn key_down(&mut self, key: u8) {
+        self.keypad.press(key);
+        if self.key_wait == KeyWait::Waiting {
+            self.key_wait = match self.quirks.key_wait {
+                KeyWaitMode::OnRelease => KeyWait::Pressed(key & 0xF),
+                KeyWaitMode::OnPress => KeyWait::Done(key & 0xF),
+            };
         }
     }
 
-    fn scancode_to_keypad(&mut self, scancode: Option<Scancode>) -> Option<u8> {
-        match scancode {
-            Some(Scancode::Num1) => Some(0x1),
-            Some(Scancode::Num2) => Some(0x2),
-            Some(Scancode::Num3) => Some(0x3),
-            Some(Scancode::Num4) => Some(0xC),
-            Some(Scancode::Q) => Some(0x4),
-            Some(Scancode::W) => Some(0x5),
-            Some(Scancode::E) => Some(0x6),
-            Some(Scancode::R) => Some(0xD),
-            Some(Scancode::A) => Some(0x7),
-            Some(Scancode::S) => Some(0x8),
-            Some(Scancode::D) => Some(0x9),
-            Some(Scancode::F) => Some(0xE),
-            Some(Scancode::Z) => Some(0xA),
-            Some(Scancode::X) => Some(0x0),
-            Some(Scancode::C) => Some(0xB),
-            Some(Scancode::V) => Some(0xF),
-            _ => None
+    pub fn key_up(&mut self, key: u8) {
+        self.keypad.release(key);
+        if self.key_wait == KeyWait::Pressed(key & 0xF) {
+            self.key_wait = KeyWait::Done(key & 0xF);
         }
     }
 
-    pub fn handle_keydown(&mut self, scancode: Option<Scancode>) {
-        self.pressed_key = self.scancode_to_keypad(scancode);
+    pub fn is_key_pressed(&self, key: u8) -> bool {
+        self.keypad.is_pressed(key)
     }
 
-    pub fn execute(&mut self, instruction: Instruction) {
+    pub fn execute(&mut self, instruction: Instruction) -> Result<(), EmulationError> {
         match instruction {
+            Instruction::NoOperation => {},
             Instruction::ClearScreen => {
-                self.display = [[0u8; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+                self.display.clear();
             },
             Instruction::ReturnFromSubroutine => {
+                self.pc = self.stack.pop().ok_or(self.fault(Fault::StackUnderflow))?;
                 self.sp -= 1;
-                self.pc = self.stack.pop().unwrap();
             },
             Instruction::Jump { address } => {
                 self.pc = address;
             },
             Instruction::CallSubroutine { address } => {
+                if self.stack.len() >= STACK_SIZE {
+                    return Err(self.fault(Fault::StackOverflow));
+                }
                 self.stack.push(self.pc);
                 self.sp += 1;
                 self.pc = address;
             },
             Instruction::SkipIfEqual { register, byte } => {
                 if self.registers[register] == byte {
-                    self.pc += 2;
+                    self.skip_next_instruction();
                 }
             },
             Instruction::SkipIfNotEqual { register, byte } => {
                 if self.registers[register] != byte {
-                    self.pc += 2;
+                    self.skip_next_instruction();
                 }
             },
             Instruction::SkipIfRegisterEqual { register1, register2 } => {
                 if self.registers[register1] == self.registers[register2] {
-                    self.pc += 2;
+                    self.skip_next_instruction();
                 }
             },
             Instruction::LoadByteIntoRegister { register, byte } => {
@@ -275,105 +548,117 @@ impl CHIP8 {
                 self.registers[register1] = self.registers[register2];
             },
             Instruction::OrRegisters { register1, register2 } => {
-                self.registers[register1] = self.registers[register1] | self.registers[register2];
+                self.registers[register1] |= self.registers[register2];
+                if self.quirks.logic_resets_vf {
+                    self.registers[0xF_u8] = 0;
+                }
             },
             Instruction::AndRegisters { register1, register2 } => {
-                self.registers[register1] = self.registers[register1] & self.registers[register2];
+                self.registers[register1] &= self.registers[register2];
+                if self.quirks.logic_resets_vf {
+                    self.registers[0xF_u8] = 0;
+                }
             },
             Instruction::XorRegisters { register1, register2 } => {
-                self.registers[register1] = self.registers[register1] ^ self.registers[register2];
+                self.registers[register1] ^= self.registers[register2];
+                if self.quirks.logic_resets_vf {
+                    self.registers[0xF_u8] = 0;
+                }
             },
             Instruction::AddRegisters { register1, register2 } => {
-                let (res, overflow) = self.registers[register1].carrying_add(self.registers[register2], false);
+                let (res, overflow) = self.registers[register1].overflowing_add(self.registers[register2]);
                 self.registers[register1] = res;
                 self.registers[0xF_u8] = if overflow { 1 } else { 0 };
             },
             Instruction::SubRegisters { register1, register2 } => {
-                let (res, borrow) = self.registers[register1].borrowing_sub(self.registers[register2], false);
+                let (res, borrow) = self.registers[register1].overflowing_sub(self.registers[register2]);
                 self.registers[register1] = res;
                 self.registers[0xF_u8] = if !borrow { 1 } else { 0 };
             },
-            Instruction::ShiftRight { register } => {
-                self.registers[register] = self.registers[register] >> 1;
-                self.registers[0xF_u8] = self.registers[register] & 1;
+            Instruction::ShiftRight { register1, register2 } => {
+                let source = if self.quirks.shift_uses_vy { register2 } else { register1 };
+                let value = self.registers[source];
+                self.registers[register1] = value >> 1;
+                self.registers[0xF_u8] = value & 1;
             },
             Instruction::SubNRegisters { register1, register2 } => {
-                let (res, borrow) = self.registers[register2].borrowing_sub(self.registers[register1], false);
+                let (res, borrow) = self.registers[register2].overflowing_sub(self.registers[register1]);
                 self.registers[register1] = res;
-                self.registers[0xF_u8] = if borrow { 1 } else { 0 };
+                self.registers[0xF_u8] = if !borrow { 1 } else { 0 };
             },
-            Instruction::ShiftLeft { register } => {
-                self.registers[register] = self.registers[register] << 1;
-                self.registers[0xF_u8] = self.registers[register] >> 7;
+            Instruction::ShiftLeft { register1, register2 } => {
+                let source = if self.quirks.shift_uses_vy { register2 } else { register1 };
+                let value = self.registers[source];
+                self.registers[register1] = value << 1;
+                self.registers[0xF_u8] = value >> 7;
             },
             Instruction::SkipIfRegisterNotEqual { register1, register2 } => {
                 if self.registers[register1] != self.registers[register2] {
-                    self.pc += 2;
+                    self.skip_next_instruction();
                 }
             },
             Instruction::LoadAddressIntoIndex { address } => {
                 self.index = address;
             },
             Instruction::JumpToAddressPlusV0 { address } => {
-                self.pc = address + self.registers[0x0_u8] as u16;
+                let register = if self.quirks.jump_uses_vx { (address >> 8) as u8 } else { 0x0 };
+                self.pc = address + self.registers[register] as u16;
             },
             Instruction::RandomByteAndIntoRegister { register, byte } => {
-                let mut rng = rand::thread_rng();
-                let randint = rng.gen_range(0..255);
-                self.registers[register] = byte & randint;
+                self.registers[register] = byte & self.rng.byte();
             },
             Instruction::DrawSprite { register1, register2, nibble } => {
-                let coord_x = self.registers[register1] % DISPLAY_WIDTH as u8;
-                let coord_y = self.registers[register2] % DISPLAY_HEIGHT as u8;
-                self.registers[0xF_u8] = 0;
-
-                for byte in 0..(nibble as usize) {
-                    let y = (coord_y as usize + byte) % DISPLAY_HEIGHT;
-                    let sprite_byte = self.memory[self.index as usize + byte];
-
-                    for bit in 0..8 {
-                        let x = (coord_x as usize + bit) % DISPLAY_WIDTH;
-                        let sprite_pixel = (sprite_byte >> (7 - bit)) & 1;
-                        if self.display[y][x] == 1 && sprite_pixel == 1 {
-                            self.registers[0xF_u8] = 1;
-                        }
-                        self.display[y][x] ^= sprite_pixel;
+                let coord_x = self.registers[register1] as usize % self.display.width();
+                let coord_y = self.registers[register2] as usize % self.display.height();
+                // Dxy0 draws a 16x16 sprite made of 2 byte rows.
+                let (rows, width) = if nibble == 0 { (16, 16) } else { (nibble as usize, 8) };
+                let bytes_per_row = width / 8;
+                let mut address = self.index as usize;
+                let mut collision = false;
+
+                // With both XO-CHIP planes selected the sprite data for the
+                // second plane follows right after the first one.
+                for plane in (0..PLANE_COUNT).map(|p| 1u8 << p) {
+                    if self.display.selected_planes() & plane == 0 {
+                        continue;
+                    }
+                    for row in 0..rows {
+                        let bits = if bytes_per_row == 2 {
+                            u16::from_be_bytes([self.read_memory(address)?, self.read_memory(address + 1)?])
+                        } else {
+                            self.read_memory(address)? as u16
+                        };
+                        address += bytes_per_row;
+                        collision |= self.display.draw_row(coord_x, coord_y + row, bits, width, plane, self.quirks.clip_sprites);
                     }
                 }
+                self.registers[0xF_u8] = collision as u8;
             },
             Instruction::SkipIfKeyPressed { register } => {
-                match self.pressed_key {
-                    Some(keycode) => {
-                        println!("Skip if key pressed. Expecting key {:?}, got {:?}", self.registers[register], keycode);
-                        if self.registers[register] == keycode {
-                            self.pc += 2;
-                        }
-                    },
-                    _ => {}
+                if self.keypad.is_pressed(self.registers[register]) {
+                    self.skip_next_instruction();
                 }
             },
             Instruction::SkipIfKeyNotPressed { register } => {
-                match self.pressed_key {
-                    Some(keycode) => {
-                        println!("Skip if key not pressed. Expecting key {:?}, got {:?}", self.registers[register], keycode);
-                        if self.registers[register] != keycode {
-                            self.pc += 2;
-                        }
-                    },
-                    _ => {},
+                if !self.keypad.is_pressed(self.registers[register]) {
+                    self.skip_next_instruction();
                 }
             },
             Instruction::LoadDelayTimerIntoRegister { register } => {
                 self.registers[register] = self.delay_timer;
             },
             Instruction::WaitForKeyPress { register } => {
-                match self.pressed_key {
-                    Some(keycode) => {
-                        println!("Wait for key. Key: {:?}", keycode);
-                        self.registers[register] = keycode;
+                match self.key_wait {
+                    KeyWait::Done(key) => {
+                        self.registers[register] = key;
+                        self.key_wait = KeyWait::Idle;
                     },
-                    _ => {
-                        self.pc -= 2;
+                    KeyWait::Idle => {
+                        self.key_wait = KeyWait::Waiting;
+                        self.pc = self.instruction_pc;
+                    },
+                    KeyWait::Waiting | KeyWait::Pressed(_) => {
+                        self.pc = self.instruction_pc;
                     },
                 }
             },
@@ -384,48 +669,364 @@ impl CHIP8 {
                 self.sound_timer = self.registers[register];
             },
             Instruction::AddRegisterToIndex { register } => {
-                self.index += self.registers[register] as u16;
+                self.index = self.index.wrapping_add(self.registers[register] as u16);
             },
             Instruction::LoadFontLocationIntoIndex { register } => {
-                self.index = (0x50 + self.registers[register] * 5) as u16;
+                self.index = (FONT_ADDRESS + (self.registers[register] & 0xF) as usize * 5) as u16;
+            },
+            Instruction::LoadBigFontLocationIntoIndex { register } => {
+                self.index = (BIG_FONT_ADDRESS + (self.registers[register] & 0xF) as usize * 10) as u16;
             },
             Instruction::LoadBinaryCodedDecimalIntoMemory { register } => {
                 let decimal = self.registers[register];
                 let (hundreds, tens, ones) = (decimal / 100, (decimal / 10) % 10, decimal % 10);
-                self.memory[self.index as usize] = hundreds;
-                self.memory[(self.index + 1) as usize] = tens;
-                self.memory[(self.index + 2) as usize] = ones;
+                let index = self.index as usize;
+                self.write_memory(index, hundreds)?;
+                self.write_memory(index + 1, tens)?;
+                self.write_memory(index + 2, ones)?;
             },
             Instruction::LoadRegistersIntoMemory { register } => {
                 for i in 0..=register {
-                    self.memory[(self.index + i as u16) as usize] = self.registers[i];
+                    self.write_memory(self.index as usize + i as usize, self.registers[i])?;
                 }
+                self.increment_index_after_load_store(register);
             },
             Instruction::LoadMemoryIntoRegisters { register } => {
                 for i in 0..=register {
-                    self.registers[i] = self.memory[(self.index + i as u16) as usize];
+                    self.registers[i] = self.read_memory(self.index as usize + i as usize)?;
+                }
+                self.increment_index_after_load_store(register);
+            },
+            Instruction::ScrollDown { nibble } => {
+                self.display.scroll_down(nibble as usize);
+            },
+            Instruction::ScrollRight => {
+                self.display.scroll_right(4);
+            },
+            Instruction::ScrollLeft => {
+                self.display.scroll_left(4);
+            },
+            Instruction::Exit => {
+                self.halted = true;
+            },
+            Instruction::LowResolution => {
+                self.display.set_hires(false);
+            },
+            Instruction::HighResolution => {
+                self.display.set_hires(true);
+            },
+            Instruction::StoreFlags { register } => {
+                for i in 0..=register {
+                    self.flags[i as usize] = self.registers[i];
+                }
+            },
+            Instruction::LoadFlags { register } => {
+                for i in 0..=register {
+                    self.registers[i] = self.flags[i as usize];
+                }
+            },
+            Instruction::ScrollUp { nibble } => {
+                self.display.scroll_up(nibble as usize);
+            },
+            Instruction::SaveRegisterRange { register1, register2 } => {
+                for (offset, i) in Self::register_range(register1, register2).enumerate() {
+                    self.write_memory(self.index as usize + offset, self.registers[i])?;
+                }
+            },
+            Instruction::LoadRegisterRange { register1, register2 } => {
+                for (offset, i) in Self::register_range(register1, register2).enumerate() {
+                    self.registers[i] = self.read_memory(self.index as usize + offset)?;
+                }
+            },
+            Instruction::LoadLongAddressIntoIndex { address } => {
+                self.index = address;
+            },
+            Instruction::SelectPlanes { mask } => {
+                self.display.select_planes(mask);
+            },
+            Instruction::LoadAudioPattern => {
+                for i in 0..AUDIO_PATTERN_SIZE {
+                    self.audio_pattern[i] = self.read_memory(self.index as usize + i)?;
                 }
             },
-            Instruction::UnknownInstruction => panic!(),
-            _ => panic!(),
+            Instruction::LoadRegisterIntoPitch { register } => {
+                self.pitch = self.registers[register];
+            },
+            Instruction::UnknownInstruction => {
+                return Err(self.fault(Fault::UnknownInstruction));
+            },
+        }
+        Ok(())
+    }
+
+    fn fault(&self, fault: Fault) -> EmulationError {
+        EmulationError { pc: self.instruction_pc, opcode: self.opcode, fault }
+    }
+
+    fn read_memory(&self, address: usize) -> Result<u8, EmulationError> {
+        match self.memory.get(address) {
+            Some(byte) => Ok(*byte),
+            None => Err(self.fault(Fault::MemoryOutOfBounds { address })),
+        }
+    }
+
+    fn write_memory(&mut self, address: usize, value: u8) -> Result<(), EmulationError> {
+        match self.memory.get_mut(address) {
+            Some(byte) => {
+                *byte = value;
+                Ok(())
+            },
+            None => Err(self.fault(Fault::MemoryOutOfBounds { address })),
+        }
+    }
+
+    // Skips over the next instruction, which is 4 bytes long for F000 NNNN.
+    fn skip_next_instruction(&mut self) {
+        let upc = self.pc as usize;
+        let long = self.memory.get(upc..upc + 2) == Some(&[0xF0, 0x00]);
+        self.pc = self.pc.wrapping_add(if long { 4 } else { 2 });
+    }
+
+    // 5xy2/5xy3 walk from x to y, downwards if y is below x.
+    fn register_range(register1: u8, register2: u8) -> Box<dyn Iterator<Item = u8>> {
+        if register1 <= register2 {
+            Box::new(register1..=register2)
+        } else {
+            Box::new((register2..=register1).rev())
+        }
+    }
+
+    fn increment_index_after_load_store(&mut self, register: u8) {
+        self.index = self.index.wrapping_add(match self.quirks.index_increment {
+            IndexIncrement::Unchanged => 0,
+            IndexIncrement::ByX => register as u16,
+            IndexIncrement::ByXPlusOne => register as u16 + 1,
+        });
+    }
+
+    // Set once the program runs 00FD; the machine stops executing from then on.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn registers(&self) -> &[u8; REGISTER_SIZE] {
+        &self.registers.0
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    // Return addresses, oldest first.
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    // Direct writes for debuggers, bypassing the instruction set.
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    pub fn registers_mut(&mut self) -> &mut [u8; REGISTER_SIZE] {
+        &mut self.registers.0
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn set_index(&mut self, index: u16) {
+        self.index = index;
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_timer = value;
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.sound_timer = value;
+    }
+
+    // The generator behind CXNN. Seeding it makes a run repeatable.
+    pub fn rng(&self) -> Rng {
+        self.rng
+    }
+
+    pub fn set_rng(&mut self, rng: Rng) {
+        self.rng = rng;
+    }
+
+    pub fn audio_pattern(&self) -> &[u8; AUDIO_PATTERN_SIZE] {
+        &self.audio_pattern
+    }
+
+    // Rate in samples per second at which the XO-CHIP audio pattern plays.
+    pub fn audio_playback_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    pub fn step(&mut self) -> Result<(), EmulationError> {
+        if self.halted {
+            return Ok(());
+        }
+        let instruction = self.fetch()?;
+        self.execute(instruction)
+    }
+
+    // One 60 Hz tick: both timers count down towards zero and stay there.
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+    }
+
+    // Runs one 60 Hz frame: `instructions` cycles followed by a single
+    // timer tick, so the timers keep the same pace at any CPU speed. A
+    // fault ends the frame early, before the timers are touched.
+    pub fn run_frame(&mut self, instructions: usize) -> Result<(), EmulationError> {
+        for _ in 0..instructions {
+            self.step()?;
         }
+        self.tick_timers();
+        Ok(())
     }
 
-    pub fn get_pixels_to_draw(&mut self) -> Vec<Point> {
-        self.display.iter().enumerate()
-                           .flat_map(|(y, row)| {
-                               row.iter().enumerate()
-                                         .filter(|&(_, &pixel)| pixel == 1)
-                                         .map(move |(x, _)| Point::new(x as i32, y as i32))
-                           })
-                           .collect()
+    pub fn is_sound_playing(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    // Snapshot of the whole machine, in the format described in savestate.rs.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.u32(self.memory.len() as u32);
+        writer.bytes(&self.memory);
+        self.display.save_state(&mut writer);
+        writer.bytes(&self.registers.0);
+        writer.bytes(&self.flags);
+        writer.u8(self.sp);
+        for &address in self.stack.iter() {
+            writer.u16(address);
+        }
+        writer.u16(self.pc);
+        writer.u16(self.index);
+        writer.u8(self.delay_timer);
+        writer.u8(self.sound_timer);
+        writer.u16((0..KEYPAD_SIZE as u8).filter(|&key| self.keypad.is_pressed(key)).fold(0, |bits, key| bits | 1 << key));
+        let (key_wait, key) = match self.key_wait {
+            KeyWait::Idle => (0, 0),
+            KeyWait::Waiting => (1, 0),
+            KeyWait::Pressed(key) => (2, key),
+            KeyWait::Done(key) => (3, key),
+        };
+        writer.u8(key_wait);
+        writer.u8(key);
+        self.quirks.save_state(&mut writer);
+        writer.bool(self.halted);
+        writer.bytes(&self.audio_pattern);
+        writer.u8(self.pitch);
+        writer.u64(self.rng.state());
+        writer.finish()
+    }
+
+    // Replaces the whole machine with a snapshot. On error nothing changes.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(data)?;
+        let memory_size = reader.u32()? as usize;
+        if memory_size != MEMORY_SIZE && memory_size != XO_CHIP_MEMORY_SIZE {
+            return Err(StateError::Invalid("memory size"));
+        }
+        let memory = reader.bytes(memory_size)?.to_vec();
+        let display = Display::load_state(&mut reader)?;
+        let registers = Registers(reader.array()?);
+        let flags = reader.array()?;
+        let sp = reader.u8()?;
+        if sp as usize > STACK_SIZE {
+            return Err(StateError::Invalid("stack pointer"));
+        }
+        let stack = (0..sp).map(|_| reader.u16()).collect::<Result<Vec<u16>, StateError>>()?;
+        let pc = reader.u16()?;
+        let index = reader.u16()?;
+        let delay_timer = reader.u8()?;
+        let sound_timer = reader.u8()?;
+        let keys = reader.u16()?;
+        let mut keypad = Keypad::default();
+        for key in (0..KEYPAD_SIZE as u8).filter(|&key| keys & 1 << key != 0) {
+            keypad.press(key);
+        }
+        let key_wait = match (reader.u8()?, reader.u8()?) {
+            (0, _) => KeyWait::Idle,
+            (1, _) => KeyWait::Waiting,
+            (2, key) if key < KEYPAD_SIZE as u8 => KeyWait::Pressed(key),
+            (3, key) if key < KEYPAD_SIZE as u8 => KeyWait::Done(key),
+            _ => return Err(StateError::Invalid("key wait state")),
+        };
+        let quirks = Quirks::load_state(&mut reader)?;
+        let halted = reader.bool()?;
+        let audio_pattern = reader.array()?;
+        let pitch = reader.u8()?;
+        let rng = Rng::new(reader.u64()?);
+        reader.finish()?;
+
+        *self = CHIP8 {
+            memory,
+            display,
+            registers,
+            flags,
+            stack,
+            pc,
+            sp,
+            index,
+            delay_timer,
+            sound_timer,
+            keypad,
+            key_wait,
+            quirks,
+            halted,
+            audio_pattern,
+            pitch,
+            rng,
+            instruction_pc: pc,
+            opcode: 0x0,
+        };
+        Ok(())
+    }
+
+    pub fn save_state_to_file(&self, path: &Path) -> io::Result<()> {
+        write(path, self.save_state())
+    }
+
+    pub fn load_state_from_file(&mut self, path: &Path) -> io::Result<()> {
+        let data = read(path)?;
+        self.load_state(&data).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
     }
 
 }
 
 #[cfg(test)]
+#[allow(clippy::field_reassign_with_default)]
 mod test {
     use super::*;
+    use crate::audio::{AudioSink, RecordingSink};
+    use crate::quirks::Platform;
     use itertools::Itertools;
     #[test]
     fn load() {
@@ -459,7 +1060,7 @@ mod test {
         ];
         let mut cpu = CHIP8::default();
         let path = Path::new("./resources/test_opcode.ch8");
-        let file = cpu.load_from_file(&path);
+        cpu.load_from_file(path).unwrap();
         let range = PROGRAM_MEMORY_START..PROGRAM_MEMORY_START + expected.len();
         assert_eq!(expected, cpu.memory[range])
     }
@@ -467,9 +1068,11 @@ mod test {
     #[test]
     fn test_load_clear_screen() {
         let mut cpu = CHIP8::default();
-        cpu.display = [[1u8; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
-        cpu.execute(Instruction::ClearScreen);
-        assert_eq!(cpu.display, [[0u8; DISPLAY_WIDTH]; DISPLAY_HEIGHT]);
+        for y in 0..DISPLAY_HEIGHT {
+            cpu.display.draw_row(0, y, 0xFFFF, 16, 1, true);
+        }
+        cpu.execute(Instruction::ClearScreen).unwrap();
+        assert_eq!(cpu.display, Display::default());
     }
 
     #[test]
@@ -480,9 +1083,9 @@ mod test {
                 cpu.pc = 0x200;
                 // The "program" is the LD instruction.
                 let program = [0x80 + reg_x, reg_y << 4];
-                cpu.load_from_slice(&program, None);
-                let instruction = cpu.fetch();
-                cpu.execute(instruction);
+                cpu.load_from_slice(&program, None).unwrap();
+                let instruction = cpu.fetch().unwrap();
+                cpu.execute(instruction).unwrap();
                 assert_eq!(cpu.pc, 0x202);
                 assert_eq!(cpu.registers[reg_x], cpu.registers[reg_y]);
             }
@@ -498,9 +1101,9 @@ mod test {
                 let [nibble_1, nibble_2] = &nibbles[..] else {panic!("Permutations are working weirdly")};
                 let expected_val = from_low_and_high(*nibble_1, *nibble_2);
                 let program = [0x60 + reg_x, expected_val];
-                cpu.load_from_slice(&program, None);
-                let instruction = cpu.fetch();
-                cpu.execute(instruction);
+                cpu.load_from_slice(&program, None).unwrap();
+                let instruction = cpu.fetch().unwrap();
+                cpu.execute(instruction).unwrap();
                 assert_eq!(cpu.pc, 0x202);
                 assert_eq!(cpu.registers[reg_x], expected_val);
             }
@@ -517,9 +1120,9 @@ mod test {
                 let expected_val =
                     cpu.registers[reg_x].wrapping_add(from_low_and_high(*nibble_1, *nibble_2));
                 let program = [0x60 + reg_x, expected_val];
-                cpu.load_from_slice(&program, None);
-                let instruction = cpu.fetch();
-                cpu.execute(instruction);
+                cpu.load_from_slice(&program, None).unwrap();
+                let instruction = cpu.fetch().unwrap();
+                cpu.execute(instruction).unwrap();
                 assert_eq!(cpu.pc, 0x202);
                 assert_eq!(cpu.registers[reg_x], expected_val);
             }
@@ -531,7 +1134,7 @@ mod test {
         let mut cpu = CHIP8::default();
         cpu.stack.push(0x200);
         cpu.sp = 1;
-        cpu.execute(Instruction::ReturnFromSubroutine);
+        cpu.execute(Instruction::ReturnFromSubroutine).unwrap();
         assert_eq!(cpu.pc, 0x200);
         assert_eq!(cpu.sp, 0);
     }
@@ -539,14 +1142,14 @@ mod test {
     #[test]
     fn test_jump_immediate() {
         let mut cpu = CHIP8::default();
-        cpu.execute(Instruction::Jump { address: 0x200 });
+        cpu.execute(Instruction::Jump { address: 0x200 }).unwrap();
         assert_eq!(cpu.pc, 0x200);
     }
 
     #[test]
     fn test_call_subroutine() {
         let mut cpu = CHIP8::default();
-        cpu.execute(Instruction::CallSubroutine { address: 0x300 });
+        cpu.execute(Instruction::CallSubroutine { address: 0x300 }).unwrap();
         assert_eq!(cpu.pc, 0x300);
         assert_eq!(cpu.stack[0], PROGRAM_MEMORY_START as u16);
         assert_eq!(cpu.sp, 1);
@@ -556,17 +1159,17 @@ mod test {
     fn test_subroutine() {
         let mut cpu = CHIP8::default();
 
-        cpu.execute(Instruction::CallSubroutine { address: 0x300 });
+        cpu.execute(Instruction::CallSubroutine { address: 0x300 }).unwrap();
         assert_eq!(cpu.stack[0], 0x200);
         assert_eq!(cpu.pc, 0x300);
 
-        cpu.execute(Instruction::LoadByteIntoRegister { register: 0, byte: 0xAB });
+        cpu.execute(Instruction::LoadByteIntoRegister { register: 0, byte: 0xAB }).unwrap();
         assert_eq!(cpu.registers[0_u8], 0xAB);
 
-        cpu.execute(Instruction::ReturnFromSubroutine);
+        cpu.execute(Instruction::ReturnFromSubroutine).unwrap();
         assert_eq!(cpu.pc, 0x200);
 
-        cpu.execute(Instruction::SkipIfEqual { register: 0, byte: 0xAB });
+        cpu.execute(Instruction::SkipIfEqual { register: 0, byte: 0xAB }).unwrap();
         assert_eq!(cpu.pc, 0x202);
     }
 
@@ -574,7 +1177,7 @@ mod test {
     fn test_skip_if_equal() {
         let mut cpu = CHIP8::default();
         cpu.registers[0] = 0xAB;
-        cpu.execute(Instruction::SkipIfEqual { register: 0, byte: 0xAB });
+        cpu.execute(Instruction::SkipIfEqual { register: 0, byte: 0xAB }).unwrap();
         assert_eq!(cpu.pc, (PROGRAM_MEMORY_START as u16) + 2);
     }
 
@@ -582,7 +1185,7 @@ mod test {
     fn test_skip_if_not_equal() {
         let mut cpu = CHIP8::default();
         cpu.registers[0] = 0xAB;
-        cpu.execute(Instruction::SkipIfNotEqual { register: 0, byte: 0xCD });
+        cpu.execute(Instruction::SkipIfNotEqual { register: 0, byte: 0xCD }).unwrap();
         assert_eq!(cpu.pc, (PROGRAM_MEMORY_START as u16) + 2);
     }
 
@@ -591,21 +1194,21 @@ mod test {
         let mut cpu = CHIP8::default();
 
         for i in 0..16 {
-            cpu.execute(Instruction::LoadByteIntoRegister { register: i, byte: i as u8 * 10 });
+            cpu.execute(Instruction::LoadByteIntoRegister { register: i, byte: i * 10 }).unwrap();
         }
 
         for i in 0..16 {
             assert_eq!(cpu.registers[i as u8], i as u8 * 10);
         }
 
-        cpu.execute(Instruction::LoadByteIntoRegister { register: 0, byte: 255 });
+        cpu.execute(Instruction::LoadByteIntoRegister { register: 0, byte: 255 }).unwrap();
 
         assert_eq!(cpu.registers[0_u8], 255);
         for i in 1..16 {
             assert_eq!(cpu.registers[i as u8], i as u8 * 10);
         }
 
-        cpu.execute(Instruction::LoadByteIntoRegister { register: 15, byte: 255 });
+        cpu.execute(Instruction::LoadByteIntoRegister { register: 15, byte: 255 }).unwrap();
 
         assert_eq!(cpu.registers[15_u8], 255);
         assert_eq!(cpu.registers[0_u8], 255);
@@ -620,7 +1223,7 @@ mod test {
         let register = 0xA;
         let byte = 0x10;
         cpu.registers[register] = 0x20;
-        cpu.execute(Instruction::AddByteToRegister { register, byte });
+        cpu.execute(Instruction::AddByteToRegister { register, byte }).unwrap();
         assert_eq!(cpu.registers[register], 0x30, "Byte was not correctly added to the register.");
     }
 
@@ -631,7 +1234,7 @@ mod test {
         let register2 = 0xB;
         cpu.registers[register1] = 0x20;
         cpu.registers[register2] = 0x10;
-        cpu.execute(Instruction::AddRegisters { register1, register2 });
+        cpu.execute(Instruction::AddRegisters { register1, register2 }).unwrap();
         assert_eq!(cpu.registers[register1], 0x30, "Registers were not correctly added.");
         assert_eq!(cpu.registers[0xF_u8], 0, "Overflow flag should be unset.");
     }
@@ -643,35 +1246,51 @@ mod test {
         let register2 = 0xB;
         cpu.registers[register1] = 0x20;
         cpu.registers[register2] = 0x10;
-        cpu.execute(Instruction::SubRegisters { register1, register2 });
+        cpu.execute(Instruction::SubRegisters { register1, register2 }).unwrap();
         assert_eq!(cpu.registers[register1], 0x10, "Registers were not correctly subtracted.");
         assert_eq!(cpu.registers[0xF_u8], 1, "Borrow flag should be set.");
     }
 
+    #[test]
+    fn test_subn_registers() {
+        let mut cpu = CHIP8::default();
+        cpu.registers[0xA_u8] = 0x10;
+        cpu.registers[0xB_u8] = 0x20;
+        cpu.execute(Instruction::SubNRegisters { register1: 0xA, register2: 0xB }).unwrap();
+        assert_eq!((cpu.registers[0xA_u8], cpu.registers[0xF_u8]), (0x10, 1));
+
+        // VY below VX borrows, which clears VF.
+        cpu.registers[0xA_u8] = 0x30;
+        cpu.execute(Instruction::SubNRegisters { register1: 0xA, register2: 0xB }).unwrap();
+        assert_eq!((cpu.registers[0xA_u8], cpu.registers[0xF_u8]), (0xF0, 0));
+    }
+
     #[test]
     fn test_load_registers_into_memory() {
         let mut cpu = CHIP8::default();
+        cpu.set_quirks(Quirks::superchip());
         let register = 0xA;
         for i in 0..=register {
-            cpu.registers[i] = i as u8;
+            cpu.registers[i] = i;
         }
         cpu.index = 0x200;
-        cpu.execute(Instruction::LoadRegistersIntoMemory { register });
+        cpu.execute(Instruction::LoadRegistersIntoMemory { register }).unwrap();
         for i in 0..=register {
-            assert_eq!(cpu.memory[(cpu.index + i as u16) as usize], i as u8, "Registers were not correctly loaded into memory.");
+            assert_eq!(cpu.memory[(cpu.index + i as u16) as usize], i, "Registers were not correctly loaded into memory.");
         }
     }
 
     #[test]
     fn test_another_load_registers_into_memory() {
         let mut cpu = CHIP8::default();
+        cpu.set_quirks(Quirks::superchip());
         cpu.index = 0x200;
 
         for i in 0..8 {
-            cpu.registers[i] = i as u8 * 10;
+            cpu.registers[i] = i * 10;
         }
 
-        cpu.execute(Instruction::LoadRegistersIntoMemory { register: 7 });
+        cpu.execute(Instruction::LoadRegistersIntoMemory { register: 7 }).unwrap();
 
         for i in 0..=7 {
             assert_eq!(cpu.memory[cpu.index as usize + i], i as u8 * 10);
@@ -681,32 +1300,693 @@ mod test {
     #[test]
     fn test_complex_scenario() {
         let mut cpu = CHIP8::default();
+        cpu.set_quirks(Quirks::superchip());
 
-        cpu.execute(Instruction::CallSubroutine { address: 0x300 });
+        cpu.execute(Instruction::CallSubroutine { address: 0x300 }).unwrap();
         assert_eq!(cpu.stack[0x0], 0x200);
         assert_eq!(cpu.pc, 0x300);
 
-        cpu.execute(Instruction::LoadByteIntoRegister { register: 1, byte: 0x05 });
+        cpu.execute(Instruction::LoadByteIntoRegister { register: 1, byte: 0x05 }).unwrap();
         assert_eq!(cpu.registers[0x1_u8], 0x05);
 
-        cpu.execute(Instruction::LoadByteIntoRegister { register: 2, byte: 0x06 });
+        cpu.execute(Instruction::LoadByteIntoRegister { register: 2, byte: 0x06 }).unwrap();
         assert_eq!(cpu.registers[0x2_u8], 0x06);
 
-        cpu.execute(Instruction::AddRegisters { register1: 1, register2: 2 });
+        cpu.execute(Instruction::AddRegisters { register1: 1, register2: 2 }).unwrap();
         assert_eq!(cpu.registers[0x1_u8], 0x0B);
 
         assert_eq!(cpu.registers[0xF_u8], 0x00);
 
-        cpu.execute(Instruction::SubRegisters { register1: 1, register2: 2 });
+        cpu.execute(Instruction::SubRegisters { register1: 1, register2: 2 }).unwrap();
         assert_eq!(cpu.registers[0x1_u8], 0x05);
 
         assert_eq!(cpu.registers[0xF_u8], 0x01); // 1 - 6
 
-        cpu.execute(Instruction::LoadRegistersIntoMemory { register: 1 });
+        cpu.execute(Instruction::LoadRegistersIntoMemory { register: 1 }).unwrap();
         assert_eq!(cpu.memory[(cpu.index + 1) as usize], 0x05);
 
-        cpu.execute(Instruction::ReturnFromSubroutine);
+        cpu.execute(Instruction::ReturnFromSubroutine).unwrap();
+        assert_eq!(cpu.pc, 0x200);
+    }
+
+    #[test]
+    fn test_tick_timers() {
+        let mut cpu = CHIP8::default();
+        cpu.registers[0] = 2;
+        cpu.execute(Instruction::LoadRegisterIntoDelayTimer { register: 0 }).unwrap();
+        cpu.execute(Instruction::LoadRegisterIntoSoundTimer { register: 0 }).unwrap();
+        assert!(cpu.is_sound_playing());
+
+        cpu.tick_timers();
+        assert_eq!((cpu.delay_timer, cpu.sound_timer), (1, 1));
+        cpu.tick_timers();
+        cpu.tick_timers();
+        assert_eq!((cpu.delay_timer, cpu.sound_timer), (0, 0));
+        assert!(!cpu.is_sound_playing());
+    }
+
+    #[test]
+    fn test_timers_independent_of_instruction_rate() {
+        // 1: jump to itself, a program that just burns cycles.
+        let program = [0x12, 0x00];
+        for instructions_per_frame in [1, 10, 1000] {
+            let mut cpu = CHIP8::default();
+            cpu.load_from_slice(&program, None).unwrap();
+            cpu.delay_timer = 60;
+            for _ in 0..30 {
+                cpu.run_frame(instructions_per_frame).unwrap();
+            }
+            assert_eq!(cpu.delay_timer, 30);
+        }
+    }
+
+    #[test]
+    fn test_delay_timer_loop() {
+        // Set the delay timer to 3, then spin until it reads back zero.
+        let program = [
+            0x60, 0x03, // LD V0, 3
+            0xF0, 0x15, // LD DT, V0
+            0xF1, 0x07, // LD V1, DT
+            0x31, 0x00, // SE V1, 0
+            0x12, 0x04, // JP 0x204
+            0x12, 0x0A, // JP 0x20A
+        ];
+        let mut cpu = CHIP8::default();
+        cpu.load_from_slice(&program, None).unwrap();
+        let mut frames = 0;
+        while cpu.pc != 0x20A {
+            cpu.run_frame(100).unwrap();
+            frames += 1;
+        }
+        // Three ticks to count down, then one more frame to observe zero.
+        assert_eq!(frames, 4);
+    }
+
+    #[test]
+    fn test_skip_if_key_pressed() {
+        let mut cpu = CHIP8::default();
+        cpu.registers[0] = 0xA;
+        cpu.execute(Instruction::SkipIfKeyPressed { register: 0 }).unwrap();
         assert_eq!(cpu.pc, 0x200);
+
+        cpu.key_down(0xA);
+        cpu.execute(Instruction::SkipIfKeyPressed { register: 0 }).unwrap();
+        assert_eq!(cpu.pc, 0x202);
+
+        cpu.key_up(0xA);
+        cpu.execute(Instruction::SkipIfKeyPressed { register: 0 }).unwrap();
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn test_skip_if_key_not_pressed() {
+        let mut cpu = CHIP8::default();
+        cpu.registers[0] = 0x3;
+        // No key held at all must skip.
+        cpu.execute(Instruction::SkipIfKeyNotPressed { register: 0 }).unwrap();
+        assert_eq!(cpu.pc, 0x202);
+
+        cpu.key_down(0x3);
+        cpu.execute(Instruction::SkipIfKeyNotPressed { register: 0 }).unwrap();
+        assert_eq!(cpu.pc, 0x202);
+
+        cpu.key_up(0x3);
+        cpu.execute(Instruction::SkipIfKeyNotPressed { register: 0 }).unwrap();
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn test_multiple_keys_held() {
+        let mut cpu = CHIP8::default();
+        cpu.key_down(0x1);
+        cpu.key_down(0xF);
+        for key in 0..KEYPAD_SIZE as u8 {
+            assert_eq!(cpu.is_key_pressed(key), key == 0x1 || key == 0xF);
+        }
+
+        cpu.registers[0] = 0x1;
+        cpu.registers[1] = 0xF;
+        cpu.execute(Instruction::SkipIfKeyPressed { register: 0 }).unwrap();
+        cpu.execute(Instruction::SkipIfKeyPressed { register: 1 }).unwrap();
+        assert_eq!(cpu.pc, 0x204);
+
+        cpu.key_up(0x1);
+        assert!(!cpu.is_key_pressed(0x1));
+        assert!(cpu.is_key_pressed(0xF));
+    }
+
+    #[test]
+    fn test_wait_for_key_press_and_release() {
+        // LD V5, K followed by a jump to itself.
+        let program = [0xF5, 0x0A, 0x12, 0x02];
+        let mut cpu = CHIP8::default();
+        cpu.load_from_slice(&program, None).unwrap();
+        cpu.delay_timer = 10;
+
+        cpu.run_frame(10).unwrap();
+        assert_eq!(cpu.pc, 0x200);
+
+        cpu.key_down(0x7);
+        cpu.run_frame(10).unwrap();
+        assert_eq!(cpu.pc, 0x200, "Fx0A must not finish while the key is held.");
+
+        cpu.key_up(0x7);
+        cpu.run_frame(10).unwrap();
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.registers[0x5_u8], 0x7);
+        assert_eq!(cpu.delay_timer, 7, "Timers must keep running while waiting.");
+    }
+
+    #[test]
+    fn test_wait_for_key_ignores_earlier_taps() {
+        let program = [0xF0, 0x0A, 0xF1, 0x0A];
+        let mut cpu = CHIP8::default();
+        cpu.load_from_slice(&program, None).unwrap();
+
+        cpu.step().unwrap();
+        cpu.key_down(0x2);
+        cpu.key_up(0x2);
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.registers[0x0_u8]), (0x202, 0x2));
+
+        // The tap above must not also satisfy the second Fx0A.
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x202);
+
+        cpu.key_down(0x9);
+        cpu.key_up(0x9);
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.registers[0x1_u8]), (0x204, 0x9));
+    }
+
+    #[test]
+    fn test_wait_for_key_on_press() {
+        let program = [0xF3, 0x0A];
+        let mut cpu = CHIP8::default();
+        cpu.set_quirks(Quirks { key_wait: KeyWaitMode::OnPress, ..Quirks::default() });
+        cpu.load_from_slice(&program, None).unwrap();
+
+        cpu.step().unwrap();
+        cpu.key_down(0xC);
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.registers[0x3_u8]), (0x202, 0xC));
+    }
+
+    #[test]
+    fn test_shift_quirk() {
+        for (platform, expected) in [(Platform::CosmacVip, 0x04), (Platform::SuperChip, 0x40)] {
+            let mut cpu = CHIP8::default();
+            cpu.set_quirks(platform.quirks());
+            cpu.registers[0] = 0x81;
+            cpu.registers[1] = 0x08;
+            cpu.execute(Instruction::ShiftRight { register1: 0, register2: 1 }).unwrap();
+            assert_eq!(cpu.registers[0_u8], expected, "{:?}", platform);
+            assert_eq!(cpu.registers[0xF_u8], if platform == Platform::CosmacVip { 0 } else { 1 });
+        }
+    }
+
+    #[test]
+    fn test_shift_flag_is_shifted_out_bit() {
+        let mut cpu = CHIP8::default();
+        cpu.registers[0] = 0x81;
+        cpu.execute(Instruction::ShiftLeft { register1: 0, register2: 0 }).unwrap();
+        assert_eq!(cpu.registers[0_u8], 0x02);
+        assert_eq!(cpu.registers[0xF_u8], 1);
+
+        // With VF as the target the flag wins over the result.
+        cpu.registers[0xF] = 0x02;
+        cpu.execute(Instruction::ShiftRight { register1: 0xF, register2: 0xF }).unwrap();
+        assert_eq!(cpu.registers[0xF_u8], 0);
+    }
+
+    #[test]
+    fn test_index_increment_quirk() {
+        let expected = [
+            (Platform::CosmacVip, 0x304),
+            (Platform::Chip48, 0x303),
+            (Platform::SuperChip, 0x300),
+        ];
+        for (platform, index) in expected {
+            let mut cpu = CHIP8::default();
+            cpu.set_quirks(platform.quirks());
+            cpu.index = 0x300;
+            cpu.execute(Instruction::LoadRegistersIntoMemory { register: 3 }).unwrap();
+            assert_eq!(cpu.index, index, "{:?}", platform);
+            cpu.index = 0x300;
+            cpu.execute(Instruction::LoadMemoryIntoRegisters { register: 3 }).unwrap();
+            assert_eq!(cpu.index, index, "{:?}", platform);
+        }
+    }
+
+    #[test]
+    fn test_jump_quirk() {
+        let mut cpu = CHIP8::default();
+        cpu.registers[0] = 0x10;
+        cpu.registers[3] = 0x20;
+
+        cpu.set_quirks(Quirks::cosmac_vip());
+        cpu.execute(Instruction::JumpToAddressPlusV0 { address: 0x345 }).unwrap();
+        assert_eq!(cpu.pc, 0x355);
+
+        cpu.set_quirks(Quirks::superchip());
+        cpu.execute(Instruction::JumpToAddressPlusV0 { address: 0x345 }).unwrap();
+        assert_eq!(cpu.pc, 0x365);
+    }
+
+    #[test]
+    fn test_logic_resets_vf_quirk() {
+        let mut cpu = CHIP8::default();
+        cpu.set_quirks(Quirks::superchip());
+        cpu.registers[0xF] = 1;
+        cpu.execute(Instruction::OrRegisters { register1: 0, register2: 1 }).unwrap();
+        assert_eq!(cpu.registers[0xF_u8], 1);
+
+        cpu.set_quirks(Quirks::cosmac_vip());
+        cpu.execute(Instruction::AndRegisters { register1: 0, register2: 1 }).unwrap();
+        assert_eq!(cpu.registers[0xF_u8], 0);
+    }
+
+    #[test]
+    fn test_sprite_clipping_quirk() {
+        for (quirks, wrapped) in [(Quirks::cosmac_vip(), 0), (Quirks::xochip(), 1)] {
+            let mut cpu = CHIP8::default();
+            cpu.set_quirks(quirks);
+            cpu.memory[0x300] = 0xFF;
+            cpu.memory[0x301] = 0xFF;
+            cpu.index = 0x300;
+            cpu.registers[0] = 60;
+            cpu.registers[1] = 31;
+            cpu.execute(Instruction::DrawSprite { register1: 0, register2: 1, nibble: 2 }).unwrap();
+            assert_eq!(cpu.display.get(63, 31), 1);
+            assert_eq!(cpu.display.get(0, 31), wrapped);
+            assert_eq!(cpu.display.get(60, 0), wrapped);
+        }
+    }
+
+    #[test]
+    fn test_superchip_decode() {
+        let program = [
+            0x00, 0xC3, 0x00, 0xFB, 0x00, 0xFC, 0x00, 0xFD, 0x00, 0xFE, 0x00, 0xFF,
+            0xF4, 0x30, 0xF5, 0x75, 0xF6, 0x85,
+        ];
+        let mut cpu = CHIP8::default();
+        cpu.load_from_slice(&program, None).unwrap();
+        let decoded: Vec<_> = (0..9).map(|_| format!("{:?}", cpu.fetch().unwrap())).collect();
+        assert_eq!(decoded, [
+            "ScrollDown { nibble: 3 }",
+            "ScrollRight",
+            "ScrollLeft",
+            "Exit",
+            "LowResolution",
+            "HighResolution",
+            "LoadBigFontLocationIntoIndex { register: 4 }",
+            "StoreFlags { register: 5 }",
+            "LoadFlags { register: 6 }",
+        ]);
+    }
+
+    #[test]
+    fn test_hires_large_sprite() {
+        let mut cpu = CHIP8::default();
+        cpu.execute(Instruction::HighResolution).unwrap();
+        assert_eq!((cpu.display.width(), cpu.display.height()), (128, 64));
+
+        for i in 0..32 {
+            cpu.memory[0x300 + i] = 0xFF;
+        }
+        cpu.index = 0x300;
+        cpu.registers[0] = 100;
+        cpu.registers[1] = 40;
+        cpu.execute(Instruction::DrawSprite { register1: 0, register2: 1, nibble: 0 }).unwrap();
+        assert_eq!(cpu.registers[0xF_u8], 0);
+        assert_eq!(cpu.display.rows().flatten().filter(|&p| p == 1).count(), 256);
+        assert_eq!((cpu.display.get(100, 40), cpu.display.get(115, 55)), (1, 1));
+
+        cpu.execute(Instruction::DrawSprite { register1: 0, register2: 1, nibble: 0 }).unwrap();
+        assert_eq!(cpu.registers[0xF_u8], 1);
+
+        cpu.execute(Instruction::LowResolution).unwrap();
+        assert_eq!((cpu.display.width(), cpu.display.height()), (64, 32));
     }
 
+    #[test]
+    fn test_superchip_scroll() {
+        let mut cpu = CHIP8::default();
+        cpu.memory[0x300] = 0x80;
+        cpu.index = 0x300;
+        cpu.execute(Instruction::DrawSprite { register1: 0, register2: 0, nibble: 1 }).unwrap();
+        cpu.execute(Instruction::ScrollDown { nibble: 3 }).unwrap();
+        cpu.execute(Instruction::ScrollRight).unwrap();
+        assert_eq!(cpu.display.get(4, 3), 1);
+        cpu.execute(Instruction::ScrollLeft).unwrap();
+        assert_eq!(cpu.display.get(0, 3), 1);
+    }
+
+    #[test]
+    fn test_big_font() {
+        let mut cpu = CHIP8::default();
+        cpu.load_font();
+        cpu.registers[0] = 8;
+        cpu.execute(Instruction::LoadBigFontLocationIntoIndex { register: 0 }).unwrap();
+        assert_eq!(cpu.index as usize, BIG_FONT_ADDRESS + 80);
+        assert_eq!(cpu.memory[cpu.index as usize..cpu.index as usize + 10],
+                   [0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn test_flags_survive_register_changes() {
+        let mut cpu = CHIP8::default();
+        for i in 0..8 {
+            cpu.registers[i] = i + 1;
+        }
+        cpu.execute(Instruction::StoreFlags { register: 7 }).unwrap();
+        cpu.registers = Registers([0u8; REGISTER_SIZE]);
+        cpu.execute(Instruction::LoadFlags { register: 3 }).unwrap();
+        assert_eq!(cpu.registers.0[..5], [1, 2, 3, 4, 0]);
+    }
+
+    #[test]
+    fn test_exit_halts() {
+        let program = [0x00, 0xFD, 0x60, 0x01];
+        let mut cpu = CHIP8::default();
+        cpu.load_from_slice(&program, None).unwrap();
+        cpu.run_frame(10).unwrap();
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.registers[0_u8], 0);
+    }
+
+    #[test]
+    fn test_xochip_memory_size() {
+        assert_eq!(CHIP8::default().memory.len(), MEMORY_SIZE);
+        let mut cpu = CHIP8::new(Platform::XoChip);
+        assert_eq!(cpu.memory.len(), XO_CHIP_MEMORY_SIZE);
+        assert_eq!(cpu.quirks(), Quirks::xochip());
+
+        // i := long 0xF000, then store V0 there.
+        cpu.load_from_slice(&[0xF0, 0x00, 0xF0, 0x00, 0xF0, 0x55], None).unwrap();
+        cpu.registers[0] = 0xAB;
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.index), (0x204, 0xF000));
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory[0xF000], 0xAB);
+    }
+
+    #[test]
+    fn test_skip_over_long_load() {
+        let program = [
+            0x30, 0x00, // SE V0, 0
+            0xF0, 0x00, 0x12, 0x34, // LD I, long 0x1234
+            0x60, 0x01, // LD V0, 1
+        ];
+        let mut cpu = CHIP8::new(Platform::XoChip);
+        cpu.load_from_slice(&program, None).unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x206);
+        cpu.step().unwrap();
+        assert_eq!((cpu.index, cpu.registers[0_u8]), (0x0, 1));
+    }
+
+    #[test]
+    fn test_register_ranges() {
+        let mut cpu = CHIP8::new(Platform::XoChip);
+        cpu.index = 0x400;
+        for i in 0..16 {
+            cpu.registers[i] = i * 3;
+        }
+        cpu.execute(Instruction::SaveRegisterRange { register1: 2, register2: 5 }).unwrap();
+        assert_eq!(cpu.memory[0x400..0x404], [6, 9, 12, 15]);
+        assert_eq!(cpu.index, 0x400);
+
+        cpu.execute(Instruction::SaveRegisterRange { register1: 5, register2: 2 }).unwrap();
+        assert_eq!(cpu.memory[0x400..0x404], [15, 12, 9, 6]);
+
+        cpu.execute(Instruction::LoadRegisterRange { register1: 8, register2: 11 }).unwrap();
+        assert_eq!(cpu.registers.0[8..12], [15, 12, 9, 6]);
+    }
+
+    #[test]
+    fn test_two_plane_sprite() {
+        let mut cpu = CHIP8::new(Platform::XoChip);
+        cpu.memory[0x300] = 0b1100_0000; // Plane 1.
+        cpu.memory[0x301] = 0b0110_0000; // Plane 2.
+        cpu.index = 0x300;
+        cpu.execute(Instruction::SelectPlanes { mask: 3 }).unwrap();
+        cpu.execute(Instruction::DrawSprite { register1: 0, register2: 0, nibble: 1 }).unwrap();
+        assert_eq!((cpu.display.get(0, 0), cpu.display.get(1, 0), cpu.display.get(2, 0)), (1, 3, 2));
+        assert_eq!(cpu.registers[0xF_u8], 0);
+
+        // Drawing on plane 2 alone only collides with plane 2 pixels.
+        cpu.execute(Instruction::SelectPlanes { mask: 2 }).unwrap();
+        cpu.index = 0x300;
+        cpu.execute(Instruction::DrawSprite { register1: 0, register2: 0, nibble: 1 }).unwrap();
+        assert_eq!((cpu.display.get(0, 0), cpu.display.get(1, 0)), (3, 1));
+        assert_eq!(cpu.registers[0xF_u8], 1);
+
+        cpu.execute(Instruction::ClearScreen).unwrap();
+        assert_eq!((cpu.display.get(0, 0), cpu.display.get(1, 0)), (1, 1));
+    }
+
+    #[test]
+    fn test_audio_pattern_and_pitch() {
+        let mut cpu = CHIP8::new(Platform::XoChip);
+        for i in 0..AUDIO_PATTERN_SIZE {
+            cpu.memory[0x500 + i] = i as u8;
+        }
+        cpu.index = 0x500;
+        let program = [0xF0, 0x02, 0xF3, 0x3A];
+        cpu.load_from_slice(&program, None).unwrap();
+        assert_eq!(cpu.audio_playback_rate(), 4000.0);
+
+        cpu.registers[3] = 112;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.audio_pattern()[15], 15);
+        assert_eq!(cpu.audio_playback_rate(), 8000.0);
+    }
+
+    #[test]
+    fn test_unknown_instruction_fault() {
+        let mut cpu = CHIP8::default();
+        cpu.load_from_slice(&[0x60, 0x01, 0xE0, 0x00], None).unwrap();
+        cpu.step().unwrap();
+        let error = cpu.step().unwrap_err();
+        assert_eq!(error, EmulationError { pc: 0x202, opcode: 0xE000, fault: Fault::UnknownInstruction });
+    }
+
+    #[test]
+    fn test_stack_faults() {
+        let mut cpu = CHIP8::default();
+        cpu.load_from_slice(&[0x00, 0xEE], None).unwrap();
+        let error = cpu.step().unwrap_err();
+        assert_eq!((error.pc, error.opcode, error.fault), (0x200, 0x00EE, Fault::StackUnderflow));
+        assert_eq!(cpu.sp, 0);
+
+        // A subroutine calling itself overflows after STACK_SIZE calls.
+        let mut cpu = CHIP8::default();
+        cpu.load_from_slice(&[0x22, 0x00], None).unwrap();
+        for _ in 0..STACK_SIZE {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.step().unwrap_err().fault, Fault::StackOverflow);
+    }
+
+    #[test]
+    fn test_memory_faults() {
+        let mut cpu = CHIP8::default();
+        cpu.index = 0xFFE;
+        cpu.registers[0] = 123;
+        assert_eq!(cpu.execute(Instruction::LoadBinaryCodedDecimalIntoMemory { register: 0 }).unwrap_err().fault,
+                   Fault::MemoryOutOfBounds { address: 0x1000 });
+        assert_eq!(cpu.execute(Instruction::LoadRegistersIntoMemory { register: 3 }).unwrap_err().fault,
+                   Fault::MemoryOutOfBounds { address: 0x1000 });
+        assert_eq!(cpu.execute(Instruction::LoadMemoryIntoRegisters { register: 2 }).unwrap_err().fault,
+                   Fault::MemoryOutOfBounds { address: 0x1000 });
+        assert_eq!(cpu.execute(Instruction::DrawSprite { register1: 0, register2: 0, nibble: 5 }).unwrap_err().fault,
+                   Fault::MemoryOutOfBounds { address: 0x1000 });
+    }
+
+    #[test]
+    fn test_pc_out_of_bounds() {
+        let mut cpu = CHIP8::default();
+        cpu.pc = 0xFFF;
+        let error = cpu.fetch().unwrap_err();
+        assert_eq!((error.pc, error.fault), (0xFFF, Fault::PcOutOfBounds));
+
+        // Running off the end of memory through plain execution.
+        let mut cpu = CHIP8::default();
+        cpu.load_from_slice(&[0x1F, 0xFE], None).unwrap();
+        cpu.load_from_slice(&[0x60, 0x00], Some(0xFFE)).unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.step().unwrap_err().fault, Fault::PcOutOfBounds);
+    }
+
+    #[test]
+    fn test_fault_stops_frame() {
+        let mut cpu = CHIP8::default();
+        cpu.load_from_slice(&[0xFF, 0xFF], None).unwrap();
+        cpu.delay_timer = 5;
+        assert!(cpu.run_frame(10).is_err());
+        assert_eq!(cpu.delay_timer, 5);
+    }
+
+    #[test]
+    fn test_load_from_slice_bounds() {
+        let mut cpu = CHIP8::default();
+        assert!(cpu.load_from_slice(&[0x00; MEMORY_SIZE], None).is_err());
+        assert!(cpu.load_from_slice(&[0xAA, 0xBB], Some(0xFFF)).is_err());
+        assert_eq!(cpu.memory[0xFFF], 0x00);
+        cpu.load_from_slice(&[0xAA, 0xBB], Some(0xFFE)).unwrap();
+        assert_eq!(cpu.memory[0xFFE..], [0xAA, 0xBB]);
+    }
+
+    #[test]
+    fn test_wait_for_key_at_end_of_memory() {
+        // Fetching Fx0A from 0xFFFE wraps the pc to 0 before it executes.
+        let mut cpu = CHIP8::new(Platform::XoChip);
+        cpu.load_from_slice(&[0xF0, 0x0A], Some(0xFFFE)).unwrap();
+        cpu.pc = 0xFFFE;
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0xFFFE);
+    }
+
+    #[test]
+    fn test_sound_timer_drives_audio_sink() {
+        let mut cpu = CHIP8::default();
+        // V0 = 2; ST = V0; then spin.
+        cpu.load_from_slice(&[0x60, 0x02, 0xF0, 0x18, 0x12, 0x04], None).unwrap();
+        let mut sink = RecordingSink::default();
+        for _ in 0..4 {
+            cpu.run_frame(3).unwrap();
+            sink.set_tone(cpu.is_sound_playing());
+        }
+        assert_eq!(sink.frames, [true, false, false, false]);
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        let mut cpu = CHIP8::new(Platform::XoChip);
+        cpu.load_font();
+        // V0 = 0x2A; call 0x20A; at 0x20A: draw, set ST, RND V1, wait for a key.
+        cpu.load_from_slice(&[0x60, 0x2A, 0x22, 0x0A], None).unwrap();
+        cpu.load_from_slice(&[0xA0, 0x50, 0xD0, 0x05, 0xF0, 0x18, 0xF2, 0x0A, 0x12, 0x0E], Some(0x20A)).unwrap();
+        for _ in 0..6 {
+            cpu.step().unwrap();
+        }
+        cpu.key_down(0x7);
+        cpu.flags[3] = 0x99;
+        cpu.display.set_hires(true);
+        cpu.display.draw_row(100, 60, 0xFFFF, 16, 0b10, false);
+
+        let state = cpu.save_state();
+        let mut restored = CHIP8::default();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(restored.memory, cpu.memory);
+        assert_eq!(restored.display, cpu.display);
+        assert_eq!(restored.registers.0, cpu.registers.0);
+        assert_eq!((restored.stack.clone(), restored.sp, restored.pc, restored.index), (vec![0x204], 1, cpu.pc, 0x50));
+        assert_eq!((restored.sound_timer, restored.flags[3]), (0x2A, 0x99));
+        assert_eq!(restored.key_wait, KeyWait::Pressed(0x7));
+        assert!(restored.is_key_pressed(0x7));
+        assert_eq!(restored.quirks, Quirks::xochip());
+        assert_eq!(restored.rng, cpu.rng);
+
+        // Both machines carry on the same way.
+        cpu.key_up(0x7);
+        restored.key_up(0x7);
+        cpu.run_frame(3).unwrap();
+        restored.run_frame(3).unwrap();
+        assert_eq!(restored.save_state(), cpu.save_state());
+        assert_eq!(restored.registers.0[2], 0x7);
+    }
+
+    #[test]
+    fn test_load_bad_state() {
+        let mut cpu = CHIP8::default();
+        cpu.pc = 0x300;
+        let state = cpu.save_state();
+
+        let mut other = CHIP8::default();
+        assert_eq!(other.load_state(&state[..state.len() - 1]), Err(StateError::Truncated));
+        assert_eq!(other.load_state(b"not a state"), Err(StateError::BadMagic));
+        let mut trailing = state.clone();
+        trailing.push(0);
+        assert_eq!(other.load_state(&trailing), Err(StateError::Invalid("length")));
+        assert_eq!(other.pc, PROGRAM_MEMORY_START as u16);
+
+        other.load_state(&state).unwrap();
+        assert_eq!(other.pc, 0x300);
+    }
+
+    #[test]
+    fn test_random_is_seeded() {
+        let run = |seed: u64, mask: u8| -> Vec<u8> {
+            let mut cpu = CHIP8::default();
+            cpu.set_rng(Rng::new(seed));
+            (0..2000)
+                .map(|_| {
+                    cpu.execute(Instruction::RandomByteAndIntoRegister { register: 0x3, byte: mask }).unwrap();
+                    cpu.registers.0[3]
+                })
+                .collect()
+        };
+        let values = run(1, 0xFF);
+        assert_eq!(values, run(1, 0xFF));
+        assert_ne!(values, run(2, 0xFF));
+        assert!(values.contains(&0xFF) && values.contains(&0x00));
+        assert!(run(1, 0x0F).iter().all(|&value| value <= 0x0F));
+    }
+
+    #[test]
+    fn test_mnemonics() {
+        let mnemonic = |bytes: &[u8]| Instruction::decode(bytes).unwrap().to_string();
+        assert_eq!(mnemonic(&[0x63, 0x1F]), "LD V3, 0x1F");
+        assert_eq!(mnemonic(&[0xD0, 0x15]), "DRW V0, V1, 5");
+        assert_eq!(mnemonic(&[0x12, 0xA4]), "JP 0x2A4");
+        assert_eq!(mnemonic(&[0xB2, 0x00]), "JP V0, 0x200");
+        assert_eq!(mnemonic(&[0x8A, 0xBE]), "SHL VA, VB");
+        assert_eq!(mnemonic(&[0xF5, 0x65]), "LD V5, [I]");
+        assert_eq!(mnemonic(&[0x00, 0xC4]), "SCD 4");
+        assert_eq!(mnemonic(&[0xF0, 0x00, 0x12, 0x34]), "LD I, LONG 0x1234");
+        assert_eq!(mnemonic(&[0x51, 0x32]), "SAVE V1 - V3");
+        assert_eq!(mnemonic(&[0x01, 0x23]), "???");
+        assert_eq!(Instruction::decode(&[0xF0, 0x00, 0x12]), None);
+        assert_eq!(Instruction::decode(&[0xF0, 0x00, 0x12, 0x34]).unwrap().size(), 4);
+    }
+
+    #[test]
+    fn test_every_opcode_round_trips() {
+        let mut known = 0;
+        for opcode in 0..=u16::MAX {
+            let [high, low] = opcode.to_be_bytes();
+            let instruction = Instruction::decode(&[high, low, 0x12, 0x34]).unwrap();
+            if instruction == Instruction::UnknownInstruction {
+                continue;
+            }
+            known += 1;
+            assert_eq!(instruction.encode(), Some(opcode), "{:04X} decodes as {:?}", opcode, instruction);
+        }
+        // Catches a pattern that shadows another: 0NNN 39, 1NNN-DXYN 44288,
+        // EX9E/EXA1 32, FXNN 226.
+        assert_eq!(known, 44_585);
+    }
+
+    #[test]
+    fn test_encode() {
+        let bytes = |instruction: Instruction| instruction.to_bytes();
+        assert_eq!(bytes(Instruction::ShiftRight { register1: 0x3, register2: 0xA }), Some(vec![0x83, 0xA6]));
+        assert_eq!(bytes(Instruction::LoadLongAddressIntoIndex { address: 0xBEEF }), Some(vec![0xF0, 0x00, 0xBE, 0xEF]));
+        assert_eq!(bytes(Instruction::ScrollDown { nibble: 0 }), Some(vec![0x00, 0xC0]));
+
+        // Fields too wide for the opcode are refused, not truncated.
+        assert_eq!(Instruction::Jump { address: 0x1200 }.encode(), None);
+        assert_eq!(Instruction::LoadByteIntoRegister { register: 16, byte: 0 }.encode(), None);
+        assert_eq!(Instruction::DrawSprite { register1: 0, register2: 0, nibble: 16 }.encode(), None);
+        assert_eq!(Instruction::NoOperation.encode(), None);
+        assert_eq!(Instruction::UnknownInstruction.encode(), None);
+    }
 }
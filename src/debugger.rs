@@ -0,0 +1,294 @@
+use crate::chip8::{Instruction, CHIP8};
+use crate::error::EmulationError;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Pause,
+    Step(usize),
+    Continue,
+    Break(u16),
+    Clear(u16),
+    Breakpoints,
+    Registers,
+    Help,
+}
+
+pub const HELP: &str = "\
+pause               p        stop before the next instruction
+step [count]        s [n]    run one or `count` instructions
+continue            c        run until a breakpoint
+break <address>     b <addr> stop before the instruction at address
+clear <address>     d <addr> remove a breakpoint
+breakpoints         bl       list breakpoints
+registers           r        show V0-VF, index, pc, stack and timers
+help                h        show this text";
+
+// Addresses are hexadecimal, with or without a 0x prefix.
+fn parse_address(s: &str) -> Result<u16, String> {
+    u16::from_str_radix(s.trim_start_matches("0x"), 16).map_err(|_| format!("invalid address '{}'", s))
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Command, String> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words.as_slice() {
+            ["p" | "pause"] => Ok(Command::Pause),
+            ["s" | "step"] => Ok(Command::Step(1)),
+            ["s" | "step", count] => count
+                .parse()
+                .map(Command::Step)
+                .map_err(|_| format!("invalid step count '{}'", count)),
+            ["c" | "continue"] => Ok(Command::Continue),
+            ["b" | "break", address] => parse_address(address).map(Command::Break),
+            ["d" | "clear", address] => parse_address(address).map(Command::Clear),
+            ["bl" | "breakpoints"] => Ok(Command::Breakpoints),
+            ["r" | "registers"] => Ok(Command::Registers),
+            ["h" | "help"] => Ok(Command::Help),
+            _ => Err(format!("unknown command '{}', try help", s.trim())),
+        }
+    }
+}
+
+// Why `Debugger::run_frame` handed control back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint(u16),
+    Stepped,
+    Halted,
+}
+
+// Runs a `CHIP8` one `fetch`/`execute` at a time so it can stop anywhere.
+// While paused nothing runs, not even the timers; single steps don't tick
+// them either, only whole frames do.
+#[derive(Debug, Default)]
+pub struct Debugger {
+    paused: bool,
+    steps: usize,
+    breakpoints: BTreeSet<u16>,
+    // Lets a continue or step leave the breakpoint it is sitting on.
+    resume_from: Option<u16>,
+}
+
+impl Debugger {
+    pub fn new(paused: bool) -> Debugger {
+        Debugger { paused, ..Debugger::default() }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused && self.steps == 0
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    // Applies `command` and returns what to show the user.
+    pub fn command(&mut self, command: Command, chip: &CHIP8) -> String {
+        match command {
+            Command::Pause => {
+                self.paused = true;
+                self.steps = 0;
+                format!("paused at {:#05X}", chip.pc())
+            },
+            Command::Step(count) => {
+                self.paused = true;
+                self.steps = count;
+                self.resume_from = Some(chip.pc());
+                String::new()
+            },
+            Command::Continue => {
+                self.paused = false;
+                self.steps = 0;
+                self.resume_from = Some(chip.pc());
+                String::new()
+            },
+            Command::Break(address) => {
+                self.breakpoints.insert(address);
+                format!("breakpoint at {:#05X}", address)
+            },
+            Command::Clear(address) => {
+                if self.breakpoints.remove(&address) {
+                    format!("cleared breakpoint at {:#05X}", address)
+                } else {
+                    format!("no breakpoint at {:#05X}", address)
+                }
+            },
+            Command::Breakpoints => {
+                if self.breakpoints.is_empty() {
+                    "no breakpoints".to_string()
+                } else {
+                    self.breakpoints.iter().map(|address| format!("{:#05X}", address)).collect::<Vec<_>>().join("\n")
+                }
+            },
+            Command::Registers => registers(chip),
+            Command::Help => HELP.to_string(),
+        }
+    }
+
+    // Runs up to `instructions` instructions, stopping early at a
+    // breakpoint or when a step is done. The timers tick only when a whole
+    // frame ran unpaused.
+    pub fn run_frame(&mut self, chip: &mut CHIP8, instructions: usize) -> Result<Option<Stop>, EmulationError> {
+        if self.is_paused() {
+            return Ok(None);
+        }
+        let stepping = self.paused;
+        for _ in 0..instructions {
+            if chip.is_halted() {
+                self.paused = true;
+                self.steps = 0;
+                return Ok(Some(Stop::Halted));
+            }
+            let pc = chip.pc();
+            if self.resume_from.take() != Some(pc) && self.breakpoints.contains(&pc) {
+                self.paused = true;
+                self.steps = 0;
+                return Ok(Some(Stop::Breakpoint(pc)));
+            }
+
+            let result = chip.fetch().and_then(|instruction| chip.execute(instruction));
+            if let Err(error) = result {
+                self.paused = true;
+                self.steps = 0;
+                return Err(error);
+            }
+
+            if stepping {
+                self.steps -= 1;
+                if self.steps == 0 {
+                    return Ok(Some(Stop::Stepped));
+                }
+            }
+        }
+        if !stepping {
+            chip.tick_timers();
+        }
+        Ok(None)
+    }
+}
+
+pub fn registers(chip: &CHIP8) -> String {
+    let mut text = String::new();
+    for (half, values) in chip.registers().chunks(8).enumerate() {
+        let line: Vec<String> = values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| format!("V{:X} {:02X}", half * 8 + i, value))
+            .collect();
+        writeln!(text, "{}", line.join("  ")).unwrap();
+    }
+    let stack: Vec<String> = chip.stack().iter().map(|address| format!("{:#05X}", address)).collect();
+    writeln!(text, "pc {:#05X}  index {:#05X}  delay {}  sound {}", chip.pc(), chip.index(), chip.delay_timer(), chip.sound_timer()).unwrap();
+    write!(text, "stack [{}]", stack.join(", ")).unwrap();
+    let pc = chip.pc() as usize;
+    if let (Some(&[high, low]), Some(instruction)) =
+        (chip.memory().get(pc..pc + 2), Instruction::decode(chip.memory().get(pc..).unwrap_or(&[])))
+    {
+        write!(text, "\nnext {:04X}  {}", u16::from_be_bytes([high, low]), instruction).unwrap();
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chip() -> CHIP8 {
+        let mut chip = CHIP8::default();
+        // V0 += 1; V1 += 2; jump back to the start.
+        chip.load_from_slice(&[0x70, 0x01, 0x71, 0x02, 0x12, 0x00], None).unwrap();
+        chip
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!("s".parse(), Ok(Command::Step(1)));
+        assert_eq!("step 10".parse(), Ok(Command::Step(10)));
+        assert_eq!(" c ".parse(), Ok(Command::Continue));
+        assert_eq!("b 0x2A4".parse(), Ok(Command::Break(0x2A4)));
+        assert_eq!("clear 204".parse(), Ok(Command::Clear(0x204)));
+        assert!("b".parse::<Command>().is_err());
+        assert!("step many".parse::<Command>().is_err());
+        assert!("jump 200".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_paused_does_nothing() {
+        let mut chip = chip();
+        let mut debugger = Debugger::new(true);
+        assert_eq!(debugger.run_frame(&mut chip, 10), Ok(None));
+        assert_eq!(chip.pc(), 0x200);
+    }
+
+    #[test]
+    fn test_step() {
+        let mut chip = chip();
+        let mut debugger = Debugger::new(true);
+        debugger.command(Command::Step(4), &chip);
+        assert_eq!(debugger.run_frame(&mut chip, 3), Ok(None));
+        assert_eq!(debugger.run_frame(&mut chip, 3), Ok(Some(Stop::Stepped)));
+        assert!(debugger.is_paused());
+        assert_eq!((chip.pc(), chip.registers()[0], chip.registers()[1]), (0x202, 2, 2));
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut chip = chip();
+        let mut debugger = Debugger::new(false);
+        debugger.command(Command::Break(0x202), &chip);
+        assert_eq!(debugger.run_frame(&mut chip, 10), Ok(Some(Stop::Breakpoint(0x202))));
+        assert_eq!((chip.pc(), chip.registers()[1]), (0x202, 0));
+
+        // Continuing runs the instruction under the breakpoint and stops
+        // there again on the next lap.
+        debugger.command(Command::Continue, &chip);
+        assert_eq!(debugger.run_frame(&mut chip, 10), Ok(Some(Stop::Breakpoint(0x202))));
+        assert_eq!((chip.registers()[0], chip.registers()[1]), (2, 2));
+
+        debugger.command(Command::Clear(0x202), &chip);
+        debugger.command(Command::Continue, &chip);
+        assert_eq!(debugger.run_frame(&mut chip, 9), Ok(None));
+        assert_eq!(chip.registers()[1], 8);
+    }
+
+    #[test]
+    fn test_timers_only_tick_on_full_frames() {
+        let mut chip = chip();
+        chip.load_from_slice(&[0x60, 0x05, 0xF0, 0x15, 0x12, 0x04], None).unwrap();
+        let mut debugger = Debugger::new(true);
+        debugger.command(Command::Step(2), &chip);
+        debugger.run_frame(&mut chip, 10).unwrap();
+        assert_eq!(chip.delay_timer(), 5);
+        debugger.command(Command::Continue, &chip);
+        debugger.run_frame(&mut chip, 10).unwrap();
+        assert_eq!(chip.delay_timer(), 4);
+    }
+
+    #[test]
+    fn test_fault_pauses() {
+        let mut chip = CHIP8::default();
+        chip.load_from_slice(&[0x00, 0xEE], None).unwrap();
+        let mut debugger = Debugger::new(false);
+        assert!(debugger.run_frame(&mut chip, 10).is_err());
+        assert!(debugger.is_paused());
+    }
+
+    #[test]
+    fn test_register_dump() {
+        let mut chip = chip();
+        let mut debugger = Debugger::new(true);
+        debugger.command(Command::Step(2), &chip);
+        debugger.run_frame(&mut chip, 10).unwrap();
+        let text = debugger.command(Command::Registers, &chip);
+        assert!(text.starts_with("V0 01  V1 02  V2 00"), "{}", text);
+        assert!(text.contains("\nV8 00  V9 00"));
+        assert!(text.contains("pc 0x204  index 0x000  delay 0  sound 0"));
+        assert!(text.contains("stack []"));
+        assert!(text.ends_with("next 1200  JP 0x200"));
+    }
+}
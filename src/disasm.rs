@@ -0,0 +1,235 @@
+use crate::chip8::{Instruction, PROGRAM_MEMORY_START, XO_CHIP_MEMORY_SIZE};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// What a label marks, in increasing order of precedence when one address
+// is reached several ways.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    Data,
+    Label,
+    Table,
+    Sub,
+}
+
+impl LabelKind {
+    fn name(self, address: u16) -> String {
+        let prefix = match self {
+            LabelKind::Data => "data",
+            LabelKind::Label => "label",
+            LabelKind::Table => "table",
+            LabelKind::Sub => "sub",
+        };
+        format!("{}_{:03x}", prefix, address)
+    }
+}
+
+// Listing of a ROM loaded at 0x200. Code is found by following every
+// path from the entry point: jumps, calls, both sides of a skip. Whatever
+// is never reached comes out as `db` lines. Bnnn's target depends on V0,
+// so only its base address is followed. Bytes past the end of the 64 KiB
+// address space are left out.
+pub fn disassemble(rom: &[u8]) -> String {
+    let rom = &rom[..rom.len().min(XO_CHIP_MEMORY_SIZE - PROGRAM_MEMORY_START)];
+    let origin = PROGRAM_MEMORY_START as u16;
+    let end = origin as usize + rom.len();
+    let decode_at = |address: u16| -> Option<Instruction> {
+        let offset = (address as usize).checked_sub(origin as usize)?;
+        Instruction::decode(rom.get(offset..)?)
+    };
+
+    let mut code: BTreeMap<u16, Instruction> = BTreeMap::new();
+    let mut labels: BTreeMap<u16, LabelKind> = BTreeMap::new();
+    let add_label = |labels: &mut BTreeMap<u16, LabelKind>, address: u16, kind: LabelKind| {
+        if (origin as usize..end).contains(&(address as usize)) {
+            let entry = labels.entry(address).or_insert(kind);
+            *entry = (*entry).max(kind);
+        }
+    };
+
+    let mut pending = vec![origin];
+    while let Some(address) = pending.pop() {
+        if code.contains_key(&address) {
+            continue;
+        }
+        let instruction = match decode_at(address) {
+            Some(Instruction::UnknownInstruction) | None => continue,
+            Some(instruction) => instruction,
+        };
+        code.insert(address, instruction);
+        let next = address.wrapping_add(instruction.size());
+
+        match instruction {
+            Instruction::Jump { address: target } => {
+                add_label(&mut labels, target, LabelKind::Label);
+                pending.push(target);
+            },
+            Instruction::CallSubroutine { address: target } => {
+                add_label(&mut labels, target, LabelKind::Sub);
+                pending.push(target);
+                pending.push(next);
+            },
+            Instruction::JumpToAddressPlusV0 { address: target } => {
+                add_label(&mut labels, target, LabelKind::Table);
+                pending.push(target);
+            },
+            Instruction::ReturnFromSubroutine | Instruction::Exit => {},
+            Instruction::SkipIfEqual { .. }
+            | Instruction::SkipIfNotEqual { .. }
+            | Instruction::SkipIfRegisterEqual { .. }
+            | Instruction::SkipIfRegisterNotEqual { .. }
+            | Instruction::SkipIfKeyPressed { .. }
+            | Instruction::SkipIfKeyNotPressed { .. } => {
+                pending.push(next);
+                let skipped = decode_at(next).map_or(2, |instruction| instruction.size());
+                pending.push(next.wrapping_add(skipped));
+            },
+            Instruction::LoadAddressIntoIndex { address: target }
+            | Instruction::LoadLongAddressIntoIndex { address: target } => {
+                add_label(&mut labels, target, LabelKind::Data);
+                pending.push(next);
+            },
+            _ => pending.push(next),
+        }
+    }
+
+    // A label or another instruction starting inside an instruction would
+    // have no line of its own, so such an instruction is listed as data.
+    let straddling: Vec<u16> = code
+        .iter()
+        .filter(|(&address, instruction)| {
+            (1..instruction.size()).any(|i| {
+                let inner = address.wrapping_add(i);
+                labels.contains_key(&inner) || code.contains_key(&inner)
+            })
+        })
+        .map(|(&address, _)| address)
+        .collect();
+    for address in straddling {
+        code.remove(&address);
+    }
+
+    let label_name = |address: u16| labels.get(&address).map(|kind| kind.name(address));
+    let address_of = |offset: usize| (PROGRAM_MEMORY_START + offset) as u16;
+    let mut listing = String::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let address = address_of(offset);
+        if let Some(name) = label_name(address) {
+            writeln!(listing, "{}:", name).unwrap();
+        }
+
+        if let Some(instruction) = code.get(&address) {
+            let size = instruction.size() as usize;
+            let bytes = rom[offset..offset + size]
+                .chunks(2)
+                .map(|word| format!("{:02X}{:02X}", word[0], word[1]))
+                .collect::<Vec<_>>()
+                .join(" ");
+            writeln!(listing, "    {:03X}  {:<9}  {}", address, bytes, mnemonic(instruction, label_name)).unwrap();
+            offset += size;
+            continue;
+        }
+
+        // Data runs until the next instruction or label, 8 bytes a line.
+        let mut run = vec![rom[offset]];
+        while run.len() < 8 && offset + run.len() < rom.len() {
+            let next = address_of(offset + run.len());
+            if code.contains_key(&next) || labels.contains_key(&next) {
+                break;
+            }
+            run.push(rom[offset + run.len()]);
+        }
+        let bytes: Vec<String> = run.iter().map(|byte| format!("{:#04X}", byte)).collect();
+        writeln!(listing, "    {:03X}  {:<9}  db {}", address, "", bytes.join(", ")).unwrap();
+        offset += run.len();
+    }
+    listing
+}
+
+// The instruction's mnemonic with addresses swapped for labels.
+fn mnemonic(instruction: &Instruction, label_name: impl Fn(u16) -> Option<String>) -> String {
+    match *instruction {
+        Instruction::Jump { address } => label_name(address).map(|name| format!("JP {}", name)),
+        Instruction::CallSubroutine { address } => label_name(address).map(|name| format!("CALL {}", name)),
+        Instruction::JumpToAddressPlusV0 { address } => label_name(address).map(|name| format!("JP V0, {}", name)),
+        Instruction::LoadAddressIntoIndex { address } => label_name(address).map(|name| format!("LD I, {}", name)),
+        Instruction::LoadLongAddressIntoIndex { address } => label_name(address).map(|name| format!("LD I, LONG {}", name)),
+        _ => None,
+    }
+    .unwrap_or_else(|| instruction.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_listing() {
+        let rom = [
+            0x00, 0xE0, // 200: CLS
+            0xA2, 0x0C, // 202: LD I, data_20c
+            0x22, 0x08, // 204: CALL sub_208
+            0x12, 0x06, // 206: JP label_206
+            0xD0, 0x15, // 208: DRW V0, V1, 5
+            0x00, 0xEE, // 20A: RET
+            0x3C, 0x42, 0x42, 0x3C, 0xFF, // 20C: sprite
+        ];
+        let listing = disassemble(&rom);
+        let expected = "    200  00E0       CLS
+    202  A20C       LD I, data_20c
+    204  2208       CALL sub_208
+label_206:
+    206  1206       JP label_206
+sub_208:
+    208  D015       DRW V0, V1, 5
+    20A  00EE       RET
+data_20c:
+    20C             db 0x3C, 0x42, 0x42, 0x3C, 0xFF
+";
+        assert_eq!(listing, expected);
+    }
+
+    #[test]
+    fn test_skips_and_unreachable_bytes() {
+        let rom = [
+            0x30, 0x01, // 200: SE V0, 0x01
+            0xF0, 0x00, 0x80, 0x00, // 202: LD I, LONG 0x8000
+            0x00, 0xFD, // 206: EXIT
+            0x12, 0x34, // 208: never reached
+        ];
+        let listing = disassemble(&rom);
+        assert!(listing.contains("    202  F000 8000  LD I, LONG 0x8000\n"), "{}", listing);
+        assert!(listing.contains("    206  00FD       EXIT\n"));
+        assert!(listing.ends_with("    208             db 0x12, 0x34\n"));
+    }
+
+    #[test]
+    fn test_unknown_opcodes_are_data() {
+        let listing = disassemble(&[0x01, 0x23, 0x00, 0xE0]);
+        assert_eq!(listing, "    200             db 0x01, 0x23, 0x00, 0xE0\n");
+    }
+
+    #[test]
+    fn test_label_inside_instruction() {
+        // 202 jumps into the middle of 200, which is then listed as data so
+        // that label_201 gets a line to sit on.
+        let listing = disassemble(&[0x60, 0x01, 0x12, 0x01]);
+        let expected = "    200             db 0x60
+label_201:
+    201             db 0x01
+    202  1201       JP label_201
+";
+        assert_eq!(listing, expected);
+    }
+
+    #[test]
+    fn test_rom_filling_memory() {
+        // More than fits above 0x200; the listing stops at 0xFFFF.
+        let mut rom = vec![0x12, 0x00];
+        rom.resize(XO_CHIP_MEMORY_SIZE, 0xFF);
+        let listing = disassemble(&rom);
+        assert!(listing.starts_with("label_200:\n    200  1200       JP label_200\n"), "{}", listing);
+        assert!(listing.ends_with("    FFFA             db 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF\n"), "{}", listing);
+    }
+}
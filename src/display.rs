@@ -0,0 +1,386 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+use std::fmt;
+
+pub const DISPLAY_WIDTH: usize = 64;
+pub const DISPLAY_HEIGHT: usize = 32;
+pub const HIRES_DISPLAY_WIDTH: usize = 128; // SUPER-CHIP high resolution mode.
+pub const HIRES_DISPLAY_HEIGHT: usize = 64;
+
+pub const PLANE_COUNT: usize = 2; // XO-CHIP bitplanes, giving four colors.
+
+// Framebuffer large enough for high resolution, packed as one u128 per
+// row and plane. Pixel x is bit 127 - x, so the leftmost pixel is the most
+// significant bit just like in sprite rows, and drawing a sprite row is a
+// shift, an AND for the collision check and an XOR. In low resolution
+// only the top left 64x32 pixels are in use, so every coordinate below is
+// in pixels of the current mode.
+//
+// A pixel's value (0-3) is the color index, bit n coming from plane n.
+// Clearing and scrolling only touch the selected planes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Display {
+    hires: bool,
+    selected_planes: u8,
+    planes: [[u128; HIRES_DISPLAY_HEIGHT]; PLANE_COUNT],
+}
+
+impl Default for Display {
+    fn default() -> Display {
+        Display {
+            hires: false,
+            selected_planes: 0b01,
+            planes: [[0; HIRES_DISPLAY_HEIGHT]; PLANE_COUNT],
+        }
+    }
+}
+
+impl Display {
+    pub fn width(&self) -> usize {
+        if self.hires { HIRES_DISPLAY_WIDTH } else { DISPLAY_WIDTH }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires { HIRES_DISPLAY_HEIGHT } else { DISPLAY_HEIGHT }
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    // Switching resolution clears every plane, not just the selected ones.
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.planes = [[0; HIRES_DISPLAY_HEIGHT]; PLANE_COUNT];
+    }
+
+    pub fn selected_planes(&self) -> u8 {
+        self.selected_planes
+    }
+
+    pub fn select_planes(&mut self, planes: u8) {
+        self.selected_planes = planes & ((1 << PLANE_COUNT) - 1);
+    }
+
+    pub fn clear(&mut self) {
+        for rows in self.selected() {
+            rows.fill(0);
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        let bit = HIRES_DISPLAY_WIDTH - 1 - x;
+        let plane = |plane: usize| (self.planes[plane][y] >> bit) as u8 & 1;
+        plane(0) | plane(1) << 1
+    }
+
+    // Rows of the current mode, unpacked to one pixel value per byte and
+    // cut down to the current width.
+    pub fn rows(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        (0..self.height()).map(|y| (0..self.width()).map(|x| self.get(x, y)).collect())
+    }
+
+    // The rows of one plane as stored, without copying: bit 127 - x is
+    // pixel x. Only the top `width` bits of each row are on screen, the
+    // rest stay 0.
+    pub fn plane(&self, plane: usize) -> &[u128] {
+        &self.planes[plane][..self.height()]
+    }
+
+    // The pixels of row `y` showing `color`, as a mask laid out like the
+    // rows of `plane`. Renderers can walk its set bits instead of every
+    // pixel on screen.
+    pub fn color_mask(&self, y: usize, color: u8) -> u128 {
+        (0..PLANE_COUNT).fold(self.row_mask(), |mask, plane| {
+            let row = self.planes[plane][y];
+            mask & if color & 1 << plane != 0 { row } else { !row }
+        })
+    }
+
+    // XORs a sprite row of `width` bits (most significant first) onto
+    // `plane` and returns whether any lit pixel got switched off. With
+    // `clip` set, bits that fall past the right edge are dropped instead
+    // of wrapping around to the left.
+    pub fn draw_row(&mut self, x: usize, y: usize, bits: u16, width: usize, plane: u8, clip: bool) -> bool {
+        let (screen_width, screen_height) = (self.width(), self.height());
+        if clip && (x >= screen_width || y >= screen_height) {
+            return false;
+        }
+        let (x, y) = (x % screen_width, y % screen_height);
+
+        // The sprite row against the left edge, then moved into place.
+        let sprite = (bits as u128 & ((1 << width) - 1)) << (HIRES_DISPLAY_WIDTH - width);
+        let mut mask = sprite >> x;
+        if !clip && x > 0 {
+            mask |= sprite << (screen_width - x);
+        }
+        mask &= self.row_mask();
+
+        let mut collision = false;
+        for (index, rows) in self.planes.iter_mut().enumerate() {
+            if plane & 1 << index != 0 {
+                collision |= rows[y] & mask != 0;
+                rows[y] ^= mask;
+            }
+        }
+        collision
+    }
+
+    pub fn scroll_down(&mut self, lines: usize) {
+        let height = self.height();
+        let lines = lines.min(height);
+        for rows in self.selected() {
+            rows.copy_within(..height - lines, lines);
+            rows[..lines].fill(0);
+        }
+    }
+
+    pub fn scroll_up(&mut self, lines: usize) {
+        let height = self.height();
+        let lines = lines.min(height);
+        for rows in self.selected() {
+            rows.copy_within(lines..height, 0);
+            rows[height - lines..height].fill(0);
+        }
+    }
+
+    pub fn scroll_right(&mut self, columns: usize) {
+        let (height, mask) = (self.height(), self.row_mask());
+        for rows in self.selected() {
+            for row in &mut rows[..height] {
+                *row = row.checked_shr(columns as u32).unwrap_or(0) & mask;
+            }
+        }
+    }
+
+    pub fn scroll_left(&mut self, columns: usize) {
+        let height = self.height();
+        for rows in self.selected() {
+            for row in &mut rows[..height] {
+                *row = row.checked_shl(columns as u32).unwrap_or(0);
+            }
+        }
+    }
+
+    // Save states keep one byte per pixel for the whole high resolution
+    // buffer, as before the framebuffer was packed.
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.hires);
+        writer.u8(self.selected_planes);
+        for y in 0..HIRES_DISPLAY_HEIGHT {
+            let row: Vec<u8> = (0..HIRES_DISPLAY_WIDTH).map(|x| self.get(x, y)).collect();
+            writer.bytes(&row);
+        }
+    }
+
+    pub(crate) fn load_state(reader: &mut StateReader) -> Result<Display, StateError> {
+        let mut display = Display {
+            hires: reader.bool()?,
+            selected_planes: reader.u8()?,
+            ..Display::default()
+        };
+        if display.selected_planes >= 1 << PLANE_COUNT {
+            return Err(StateError::Invalid("plane selection"));
+        }
+        for y in 0..HIRES_DISPLAY_HEIGHT {
+            let row: [u8; HIRES_DISPLAY_WIDTH] = reader.array()?;
+            for (x, &pixel) in row.iter().enumerate() {
+                if pixel >= 1 << PLANE_COUNT {
+                    return Err(StateError::Invalid("pixel"));
+                }
+                for (plane, rows) in display.planes.iter_mut().enumerate() {
+                    rows[y] |= ((pixel >> plane & 1) as u128) << (HIRES_DISPLAY_WIDTH - 1 - x);
+                }
+            }
+        }
+        Ok(display)
+    }
+
+    // The bits of a row that are on screen in the current mode.
+    fn row_mask(&self) -> u128 {
+        !0 << (HIRES_DISPLAY_WIDTH - self.width())
+    }
+
+    fn selected(&mut self) -> impl Iterator<Item = &mut [u128; HIRES_DISPLAY_HEIGHT]> {
+        let selected = self.selected_planes;
+        self.planes.iter_mut().enumerate().filter(move |(plane, _)| selected & 1 << plane != 0).map(|(_, rows)| rows)
+    }
+}
+
+// Text rendering used for headless runs: one character per pixel value.
+const TEXT_PIXELS: [char; 4] = ['.', '#', 'o', '@'];
+
+impl fmt::Display for Display {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for row in self.rows() {
+            let line: String = row.iter().map(|&pixel| TEXT_PIXELS[(pixel & 0b11) as usize]).collect();
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_draw_row_collision() {
+        let mut display = Display::default();
+        assert!(!display.draw_row(0, 0, 0b1010_0000, 8, 1, true));
+        assert_eq!((display.get(0, 0), display.get(1, 0), display.get(2, 0)), (1, 0, 1));
+        assert!(display.draw_row(0, 0, 0b1000_0000, 8, 1, true));
+        assert_eq!(display.get(0, 0), 0);
+    }
+
+    #[test]
+    fn test_resolution_switch() {
+        let mut display = Display::default();
+        assert_eq!((display.width(), display.height()), (64, 32));
+        display.draw_row(0, 0, 0xFF, 8, 1, true);
+
+        display.set_hires(true);
+        assert_eq!((display.width(), display.height()), (128, 64));
+        assert_eq!(display.rows().count(), 64);
+        assert!(display.rows().all(|row| row.len() == 128 && row.iter().all(|&p| p == 0)));
+
+        // 16 bit rows drawn at the right edge wrap within the hires width.
+        display.draw_row(120, 63, 0xFFFF, 16, 1, false);
+        assert_eq!((display.get(127, 63), display.get(0, 63), display.get(7, 63)), (1, 1, 1));
+    }
+
+    #[test]
+    fn test_scrolling() {
+        let mut display = Display::default();
+        display.draw_row(0, 0, 0b1000_0000, 8, 1, true);
+
+        display.scroll_down(2);
+        assert_eq!((display.get(0, 0), display.get(0, 2)), (0, 1));
+
+        display.scroll_right(4);
+        assert_eq!((display.get(0, 2), display.get(4, 2)), (0, 1));
+
+        display.scroll_left(4);
+        assert_eq!((display.get(0, 2), display.get(4, 2)), (1, 0));
+
+        display.scroll_up(2);
+        assert_eq!(display.get(0, 0), 1);
+
+        // Pixels pushed off screen are gone for good.
+        display.scroll_left(4);
+        display.scroll_right(4);
+        assert!(display.rows().all(|row| row.iter().all(|&p| p == 0)));
+    }
+
+    #[test]
+    fn test_text_rendering() {
+        let mut display = Display::default();
+        display.draw_row(1, 0, 0b1100_0000, 8, 0b01, true);
+        display.draw_row(2, 0, 0b1000_0000, 8, 0b10, true);
+        let text = display.to_string();
+        assert_eq!(text.lines().count(), 32);
+        assert!(text.starts_with(".#@....."));
+        assert!(text.lines().all(|line| line.len() == 64));
+    }
+
+    #[test]
+    fn test_planes() {
+        let mut display = Display::default();
+        display.draw_row(0, 0, 0b1100_0000, 8, 0b01, true);
+        display.draw_row(0, 0, 0b0110_0000, 8, 0b10, true);
+        assert_eq!((display.get(0, 0), display.get(1, 0), display.get(2, 0)), (1, 3, 2));
+
+        // Only the selected plane is cleared or scrolled.
+        display.select_planes(0b10);
+        display.scroll_down(1);
+        assert_eq!((display.get(1, 0), display.get(1, 1), display.get(2, 1)), (1, 2, 2));
+        display.clear();
+        assert_eq!((display.get(0, 0), display.get(1, 0), display.get(1, 1)), (1, 1, 0));
+    }
+
+    // The old byte per pixel framebuffer, to check the packed one against.
+    struct Reference {
+        hires: bool,
+        pixels: [[u8; HIRES_DISPLAY_WIDTH]; HIRES_DISPLAY_HEIGHT],
+    }
+
+    impl Reference {
+        fn draw_row(&mut self, x: usize, y: usize, bits: u16, width: usize, plane: u8, clip: bool) -> bool {
+            let (screen_width, screen_height) = if self.hires { (128, 64) } else { (64, 32) };
+            if clip && y >= screen_height {
+                return false;
+            }
+            let y = y % screen_height;
+            let mut collision = false;
+            for bit in 0..width {
+                if clip && x + bit >= screen_width {
+                    break;
+                }
+                if (bits >> (width - 1 - bit)) & 1 == 0 {
+                    continue;
+                }
+                let pixel = &mut self.pixels[y][(x + bit) % screen_width];
+                collision |= *pixel & plane != 0;
+                *pixel ^= plane;
+            }
+            collision
+        }
+    }
+
+    #[test]
+    fn test_matches_reference() {
+        let mut rng = crate::rng::Rng::new(5);
+        for hires in [false, true] {
+            let mut display = Display::default();
+            display.set_hires(hires);
+            let mut reference = Reference { hires, pixels: [[0; HIRES_DISPLAY_WIDTH]; HIRES_DISPLAY_HEIGHT] };
+            for _ in 0..5000 {
+                let (x, y) = (rng.byte() as usize % 140, rng.byte() as usize % 70);
+                let bits = u16::from_be_bytes([rng.byte(), rng.byte()]);
+                let width = if rng.byte() & 1 != 0 { 16 } else { 8 };
+                let plane = rng.byte() % 3 + 1;
+                let clip = rng.byte() & 1 != 0;
+                // Sprites always start on screen; only clipping can be off the edge.
+                let (x, y) = if clip { (x, y) } else { (x % display.width(), y % display.height()) };
+                let expected = reference.draw_row(x, y, bits, width, plane, clip);
+                assert_eq!(display.draw_row(x, y, bits, width, plane, clip), expected, "{} {} {:04X}", x, y, bits);
+            }
+            for (y, row) in reference.pixels.iter().enumerate() {
+                for (x, &pixel) in row.iter().enumerate() {
+                    assert_eq!(display.get(x, y), pixel, "pixel {},{}", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_packed_views() {
+        let mut display = Display::default();
+        display.draw_row(0, 3, 0b1100_0000, 8, 0b01, true);
+        display.draw_row(1, 3, 0b1000_0000, 8, 0b10, true);
+        assert_eq!(display.plane(0).len(), 32);
+        assert_eq!(display.plane(0)[3], 0b11 << 126);
+        assert_eq!(display.plane(1)[3], 1 << 126);
+        assert_eq!(display.color_mask(3, 1), 1 << 127);
+        assert_eq!(display.color_mask(3, 3), 1 << 126);
+        assert_eq!(display.color_mask(3, 2), 0);
+        // Off screen bits never show up, even for the background.
+        assert_eq!(display.color_mask(3, 0), (!0 << 64) & !(0b11 << 126));
+    }
+
+    #[test]
+    fn test_save_state_layout() {
+        let mut display = Display::default();
+        display.set_hires(true);
+        display.select_planes(0b11);
+        display.draw_row(120, 63, 0x8001, 16, 0b10, false);
+        let mut writer = StateWriter::new();
+        display.save_state(&mut writer);
+        let bytes = writer.finish();
+        // Right after the header: hires, planes, then a byte per pixel.
+        let fields = &bytes[bytes.len() - (2 + 128 * 64)..];
+        assert_eq!((fields[0], fields[1]), (1, 0b11));
+        let pixel = |x: usize, y: usize| fields[2 + y * 128 + x];
+        assert_eq!((pixel(120, 63), pixel(7, 63), pixel(121, 63)), (2, 2, 0));
+        assert_eq!(Display::load_state(&mut StateReader::new(&bytes).unwrap()), Ok(display));
+    }
+}
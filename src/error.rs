@@ -0,0 +1,39 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    UnknownInstruction,
+    StackUnderflow,
+    StackOverflow,
+    MemoryOutOfBounds { address: usize },
+    PcOutOfBounds,
+}
+
+// Raised by `fetch`/`execute` instead of panicking. `pc` and `opcode`
+// belong to the instruction that faulted, not to where `pc` points now.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EmulationError {
+    pub pc: u16,
+    pub opcode: u16,
+    pub fault: Fault,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::UnknownInstruction => write!(f, "unknown instruction"),
+            Fault::StackUnderflow => write!(f, "return with an empty stack"),
+            Fault::StackOverflow => write!(f, "call with a full stack"),
+            Fault::MemoryOutOfBounds { address } => write!(f, "memory access out of bounds at {:#06X}", address),
+            Fault::PcOutOfBounds => write!(f, "program counter ran past the end of memory"),
+        }
+    }
+}
+
+impl fmt::Display for EmulationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (pc {:#05X}, opcode {:04X})", self.fault, self.pc, self.opcode)
+    }
+}
+
+impl std::error::Error for EmulationError {}
@@ -0,0 +1,507 @@
+use crate::chip8::CHIP8;
+use crate::debugger::{Command, Debugger, Stop};
+use crate::error::{EmulationError, Fault};
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Range;
+use std::thread;
+use std::time::Duration;
+
+// GDB remote serial protocol stub, so gdb (or anything else speaking RSP)
+// can attach to a running ROM over TCP. Breakpoints and stepping go
+// through a `Debugger`, exactly like the terminal prompt.
+//
+// Registers, numbered as GDB sees them:
+//
+//   0-15   V0-VF   8 bits
+//   16     I       16 bits
+//   17     PC      16 bits
+//   18     SP      8 bits, the stack depth; read only
+//   19     DT      8 bits
+//   20     ST      8 bits
+//
+// 16 bit values are sent big endian, the way CHIP-8 stores them.
+//
+// Stop replies: S05 (SIGTRAP) after a breakpoint or step, S02 (SIGINT)
+// after a ^C, S04 (SIGILL) on an unknown instruction, S0B (SIGSEGV) on
+// any other fault, and W00 once the program runs 00FD.
+const REGISTER_COUNT: usize = 21;
+const SIGINT: &str = "S02";
+const SIGTRAP: &str = "S05";
+
+const FRAME: Duration = Duration::from_micros(1_000_000 / 60);
+
+pub struct GdbStub {
+    stream: Option<TcpStream>,
+    debugger: Debugger,
+    input: Vec<u8>,
+    no_ack: bool,
+    // Set between a c or s and the stop reply it is owed.
+    running: bool,
+    last_stop: &'static str,
+    killed: bool,
+}
+
+impl GdbStub {
+    // Waits for GDB to connect. The machine stays paused until told
+    // otherwise.
+    pub fn accept(listener: &TcpListener) -> io::Result<GdbStub> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            stream: Some(stream),
+            debugger: Debugger::new(true),
+            input: Vec::new(),
+            no_ack: false,
+            running: false,
+            last_stop: SIGTRAP,
+            killed: false,
+        })
+    }
+
+    // False once GDB has detached or the connection dropped. The machine
+    // then runs freely.
+    pub fn is_attached(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    // GDB sent k; the frontend should quit.
+    pub fn is_killed(&self) -> bool {
+        self.killed
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.debugger.is_paused()
+    }
+
+    // Handles every packet that has arrived since the last call.
+    pub fn poll(&mut self, chip: &mut CHIP8) {
+        self.receive();
+        while let Some(packet) = self.next_packet(chip) {
+            if let Some(reply) = self.handle(chip, &packet) {
+                self.send(&reply);
+            }
+        }
+    }
+
+    // One frame under GDB's control. Faults are GDB's to report while it
+    // is attached, so they only come back from here after it detached.
+    pub fn run_frame(&mut self, chip: &mut CHIP8, instructions: usize) -> Result<(), EmulationError> {
+        let stop = match self.debugger.run_frame(chip, instructions) {
+            Ok(None) => return Ok(()),
+            Ok(Some(Stop::Breakpoint(_) | Stop::Stepped)) => SIGTRAP,
+            Ok(Some(Stop::Halted)) => "W00",
+            Err(error) if !self.is_attached() => return Err(error),
+            Err(EmulationError { fault: Fault::UnknownInstruction, .. }) => "S04",
+            Err(_) => "S0B",
+        };
+        self.stopped(stop);
+        Ok(())
+    }
+
+    // Runs the machine without a window, 60 frames a second, until GDB
+    // detaches or kills it.
+    pub fn run(&mut self, chip: &mut CHIP8, instructions: usize) {
+        loop {
+            self.poll(chip);
+            if !self.is_attached() {
+                break;
+            }
+            self.run_frame(chip, instructions).expect("faults are reported to GDB");
+            thread::sleep(FRAME);
+        }
+    }
+
+    fn stopped(&mut self, stop: &'static str) {
+        self.last_stop = stop;
+        if self.running {
+            self.running = false;
+            self.send(stop);
+        }
+    }
+
+    fn receive(&mut self) {
+        let Some(stream) = self.stream.as_mut() else {
+            return;
+        };
+        let mut buffer = [0u8; 4096];
+        let result = stream.set_nonblocking(true).and_then(|()| loop {
+            match stream.read(&mut buffer) {
+                Ok(0) => break Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break stream.set_nonblocking(false),
+                Err(error) => break Err(error),
+            }
+        });
+        if result.is_err() {
+            self.detach();
+        }
+    }
+
+    // Pulls the next complete packet out of the input, acknowledging it.
+    // A ^C in between packets interrupts the machine on the spot.
+    fn next_packet(&mut self, chip: &CHIP8) -> Option<String> {
+        loop {
+            match *self.input.first()? {
+                b'$' => break,
+                0x03 => {
+                    self.input.remove(0);
+                    self.debugger.command(Command::Pause, chip);
+                    self.stopped(SIGINT);
+                },
+                _ => {
+                    self.input.remove(0);
+                },
+            }
+        }
+        let end = self.input.iter().position(|&byte| byte == b'#')?;
+        if self.input.len() < end + 3 {
+            return None;
+        }
+        let frame: Vec<u8> = self.input.drain(..end + 3).collect();
+        let data = &frame[1..end];
+        let checksum = std::str::from_utf8(&frame[end + 1..]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        if !self.no_ack {
+            let ack = if checksum == Some(checksum_of(data)) { b"+" } else { b"-" };
+            self.write(ack);
+            if ack == b"-" {
+                return self.next_packet(chip);
+            }
+        }
+        Some(String::from_utf8_lossy(data).into_owned())
+    }
+
+    fn handle(&mut self, chip: &mut CHIP8, packet: &str) -> Option<String> {
+        let (command, arguments) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => self.last_stop.to_string(),
+            "g" => (0..REGISTER_COUNT).filter_map(|number| read_register(chip, number)).map(|bytes| to_hex(&bytes)).collect(),
+            "G" => write_registers(chip, arguments).map_or("E01", |()| "OK").to_string(),
+            "p" => usize::from_str_radix(arguments, 16)
+                .ok()
+                .and_then(|number| read_register(chip, number))
+                .map_or("E01".to_string(), |bytes| to_hex(&bytes)),
+            "P" => match arguments
+                .split_once('=')
+                .and_then(|(number, value)| Some((usize::from_str_radix(number, 16).ok()?, from_hex(value)?)))
+            {
+                Some((number, value)) if write_register(chip, number, &value) => "OK".to_string(),
+                _ => "E01".to_string(),
+            },
+            "m" => parse_range(arguments)
+                .and_then(|range| chip.memory().get(range))
+                .map_or("E01".to_string(), to_hex),
+            "M" => arguments
+                .split_once(':')
+                .and_then(|(range, data)| Some((parse_range(range)?, from_hex(data)?)))
+                .filter(|(range, data)| range.len() == data.len())
+                .and_then(|(range, data)| {
+                    chip.memory_mut().get_mut(range)?.copy_from_slice(&data);
+                    Some(())
+                })
+                .map_or("E01", |()| "OK")
+                .to_string(),
+            "Z" | "z" => match parse_breakpoint(arguments) {
+                Some(address) => {
+                    let command = if command == "Z" { Command::Break(address) } else { Command::Clear(address) };
+                    self.debugger.command(command, chip);
+                    "OK".to_string()
+                },
+                None => String::new(),
+            },
+            "c" | "s" => {
+                if let Ok(address) = u16::from_str_radix(arguments, 16) {
+                    chip.set_pc(address);
+                }
+                let command = if command == "c" { Command::Continue } else { Command::Step(1) };
+                self.debugger.command(command, chip);
+                self.running = true;
+                return None;
+            },
+            "D" => {
+                self.send("OK");
+                self.detach();
+                return None;
+            },
+            "k" => {
+                self.killed = true;
+                self.detach();
+                return None;
+            },
+            "H" | "T" => "OK".to_string(),
+            _ => match packet {
+                "qAttached" => "1".to_string(),
+                "qfThreadInfo" => "m1".to_string(),
+                "qsThreadInfo" => "l".to_string(),
+                "QStartNoAckMode" => {
+                    // The OK itself is the last packet to be acknowledged.
+                    self.send("OK");
+                    self.no_ack = true;
+                    return None;
+                },
+                _ if packet.starts_with("qSupported") => "PacketSize=1000;QStartNoAckMode+;qXfer:features:read+".to_string(),
+                _ => match packet.strip_prefix("qXfer:features:read:target.xml:") {
+                    Some(range) => read_chunk(&target_xml(), range),
+                    // An empty reply tells GDB the packet isn't supported.
+                    None => String::new(),
+                },
+            },
+        };
+        Some(reply)
+    }
+
+    // Resumes free running with no breakpoints and lets go of the socket.
+    fn detach(&mut self) {
+        self.stream = None;
+        self.running = false;
+        self.debugger = Debugger::new(false);
+    }
+
+    fn send(&mut self, data: &str) {
+        self.write(packet(data).as_bytes());
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let Some(stream) = self.stream.as_mut() else {
+            return;
+        };
+        if stream.write_all(bytes).is_err() {
+            self.detach();
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn packet(data: &str) -> String {
+    format!("${}#{:02x}", data, checksum_of(data.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        write!(hex, "{:02x}", byte).unwrap();
+        hex
+    })
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// "addr,length" in hex, as the range it covers. None when the end
+// doesn't fit in a usize.
+fn parse_range(range: &str) -> Option<Range<usize>> {
+    let (address, length) = range.split_once(',')?;
+    let address = usize::from_str_radix(address, 16).ok()?;
+    let end = address.checked_add(usize::from_str_radix(length, 16).ok()?)?;
+    Some(address..end)
+}
+
+// "type,addr,kind". Software and hardware breakpoints are the same thing
+// here; watchpoints aren't supported.
+fn parse_breakpoint(arguments: &str) -> Option<u16> {
+    let mut fields = arguments.split(',');
+    match (fields.next()?, fields.next()?) {
+        ("0" | "1", address) => u16::from_str_radix(address, 16).ok(),
+        _ => None,
+    }
+}
+
+fn read_register(chip: &CHIP8, number: usize) -> Option<Vec<u8>> {
+    match number {
+        0..=15 => Some(vec![chip.registers()[number]]),
+        16 => Some(chip.index().to_be_bytes().to_vec()),
+        17 => Some(chip.pc().to_be_bytes().to_vec()),
+        18 => Some(vec![chip.stack().len() as u8]),
+        19 => Some(vec![chip.delay_timer()]),
+        20 => Some(vec![chip.sound_timer()]),
+        _ => None,
+    }
+}
+
+fn write_register(chip: &mut CHIP8, number: usize, value: &[u8]) -> bool {
+    match (number, value) {
+        (0..=15, &[byte]) => chip.registers_mut()[number] = byte,
+        (16, &[high, low]) => chip.set_index(u16::from_be_bytes([high, low])),
+        (17, &[high, low]) => chip.set_pc(u16::from_be_bytes([high, low])),
+        // The stack can't be resized from here, only left as it is.
+        (18, &[sp]) => return sp as usize == chip.stack().len(),
+        (19, &[byte]) => chip.set_delay_timer(byte),
+        (20, &[byte]) => chip.set_sound_timer(byte),
+        _ => return false,
+    }
+    true
+}
+
+// Checks the whole G packet before touching anything, so a bad one
+// leaves the registers as they were.
+fn write_registers(chip: &mut CHIP8, hex: &str) -> Option<()> {
+    let data = from_hex(hex)?;
+    let mut values = Vec::new();
+    let mut rest = data.as_slice();
+    for number in 0..REGISTER_COUNT {
+        let size = read_register(chip, number)?.len();
+        let (value, tail) = rest.split_at_checked(size)?;
+        values.push(value);
+        rest = tail;
+    }
+    if !rest.is_empty() || values[18][0] as usize != chip.stack().len() {
+        return None;
+    }
+    for (number, value) in values.into_iter().enumerate() {
+        write_register(chip, number, value);
+    }
+    Some(())
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n<feature name=\"org.chip8.core\">\n");
+    for register in 0..16 {
+        writeln!(xml, "<reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\"/>", register).unwrap();
+    }
+    xml.push_str("<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>\n");
+    xml.push_str("<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>\n");
+    for name in ["sp", "dt", "st"] {
+        writeln!(xml, "<reg name=\"{}\" bitsize=\"8\" type=\"uint8\"/>", name).unwrap();
+    }
+    xml.push_str("</feature>\n</target>\n");
+    xml
+}
+
+// qXfer reads come as "offset,length"; the reply is m for more to come
+// or l for the last chunk.
+fn read_chunk(document: &str, range: &str) -> String {
+    let Some(range) = parse_range(range) else {
+        return "E01".to_string();
+    };
+    let chunk = document.get(range.start.min(document.len())..range.end.min(document.len())).unwrap_or("");
+    let more = range.end < document.len();
+    format!("{}{}", if more { "m" } else { "l" }, chunk)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread::JoinHandle;
+
+    // A minimal scripted RSP client, speaking the protocol the way gdb does.
+    struct Client {
+        stream: TcpStream,
+        no_ack: bool,
+    }
+
+    impl Client {
+        fn send(&mut self, data: &str) -> String {
+            self.stream.write_all(packet(data).as_bytes()).unwrap();
+            self.reply()
+        }
+
+        fn send_raw(&mut self, bytes: &[u8]) {
+            self.stream.write_all(bytes).unwrap();
+        }
+
+        fn reply(&mut self) -> String {
+            let mut frame = Vec::new();
+            let mut byte = [0u8];
+            while frame.len() < 3 || frame[frame.len() - 3] != b'#' {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'$' || !frame.is_empty() {
+                    frame.push(byte[0]);
+                }
+            }
+            if !self.no_ack {
+                self.stream.write_all(b"+").unwrap();
+            }
+            let data = &frame[1..frame.len() - 3];
+            assert_eq!(format!("{:02x}", checksum_of(data)).as_bytes(), &frame[frame.len() - 2..]);
+            String::from_utf8(data.to_vec()).unwrap()
+        }
+    }
+
+    fn attach(rom: &[u8]) -> (Client, JoinHandle<CHIP8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut chip = CHIP8::default();
+        chip.load_from_slice(rom, None).unwrap();
+        let server = thread::spawn(move || {
+            let mut stub = GdbStub::accept(&listener).unwrap();
+            stub.run(&mut chip, 10);
+            chip
+        });
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        (Client { stream, no_ack: false }, server)
+    }
+
+    #[test]
+    fn test_packets() {
+        assert_eq!(packet("OK"), "$OK#9a");
+        assert_eq!(from_hex("00ff1A"), Some(vec![0x00, 0xFF, 0x1A]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(parse_breakpoint("0,2a4,2"), Some(0x2A4));
+        assert_eq!(parse_breakpoint("2,2a4,2"), None);
+        assert_eq!(read_chunk("abcdef", "0,4"), "mabcd");
+        assert_eq!(read_chunk("abcdef", "4,4"), "lef");
+    }
+
+    #[test]
+    fn test_session() {
+        // V0 += 1; V1 += 2; jump back to the start.
+        let (mut gdb, server) = attach(&[0x70, 0x01, 0x71, 0x02, 0x12, 0x00]);
+        assert!(gdb.send("qSupported:swbreak+").contains("QStartNoAckMode+"));
+        assert_eq!(gdb.send("QStartNoAckMode"), "OK");
+        gdb.no_ack = true;
+        assert_eq!(gdb.send("?"), "S05");
+        assert_eq!(gdb.send("g"), "00".repeat(16) + concat!("0000", "0200", "00", "00", "00"));
+        assert!(gdb.send("qXfer:features:read:target.xml:0,1000").contains("<reg name=\"pc\" bitsize=\"16\""));
+        assert_eq!(gdb.send("qXfer:features:read:target.xml:ffffffffffffffff,1"), "E01");
+
+        assert_eq!(gdb.send("Z0,202,2"), "OK");
+        assert_eq!(gdb.send("c"), "S05");
+        assert_eq!(gdb.send("p11"), "0202");
+        assert_eq!(gdb.send("s"), "S05");
+        assert_eq!(gdb.send("p11"), "0204");
+        assert_eq!(gdb.send("p1"), "02");
+        assert_eq!(gdb.send("c"), "S05");
+        assert_eq!(gdb.send("p0"), "02");
+
+        assert_eq!(gdb.send("m200,4"), "70017102");
+        assert_eq!(gdb.send("M202,2:7103"), "OK");
+        assert_eq!(gdb.send("m202,2"), "7103");
+        assert_eq!(gdb.send("mffff,2"), "E01");
+        assert_eq!(gdb.send("mffffffffffffffff,1"), "E01");
+        assert_eq!(gdb.send("Mffffffffffffffff,1:00"), "E01");
+        assert_eq!(gdb.send("Pf=2a"), "OK");
+        assert_eq!(gdb.send("P12=01"), "E01");
+        assert_eq!(gdb.send("Z2,202,2"), "");
+        assert_eq!(gdb.send("z0,202,2"), "OK");
+        assert_eq!(gdb.send("vMustReplyEmpty"), "");
+        assert_eq!(gdb.send("D"), "OK");
+
+        let chip = server.join().unwrap();
+        assert_eq!(chip.registers()[0xF], 0x2A);
+        assert_eq!(chip.memory()[0x203], 0x03);
+    }
+
+    #[test]
+    fn test_interrupt_and_faults() {
+        // Spins at 0x200; 0x202 returns with an empty stack.
+        let (mut gdb, server) = attach(&[0x12, 0x00, 0x00, 0xEE]);
+        gdb.send_raw(packet("c").as_bytes());
+        thread::sleep(FRAME * 3);
+        gdb.send_raw(&[0x03]);
+        assert_eq!(gdb.reply(), "S02");
+        assert_eq!(gdb.send("p11"), "0200");
+        assert_eq!(gdb.send("c202"), "S0B");
+        assert_eq!(gdb.send("?"), "S0B");
+
+        gdb.send_raw(packet("k").as_bytes());
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,401 @@
+use crate::display::{Display, HIRES_DISPLAY_HEIGHT, HIRES_DISPLAY_WIDTH};
+use crate::palette::Palette;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+// Animated GIF of the display, fed one frame per 60 Hz tick. Every image
+// covers the whole screen with the palette as the global color table.
+// Identical consecutive frames become one image shown for longer, so a
+// game idling on a title screen costs next to nothing.
+//
+// GIF delays are whole centiseconds and a 60 Hz frame is 1.67 of them.
+// Each delay is the rounded end time of the image minus the rounded start
+// time, so the delays never drift away from the real frame clock. Viewers
+// show anything under 2 centiseconds for 10 instead, so an image that
+// would get 1 is skipped and its time goes to the next one.
+const HEADER: &[u8] = b"GIF89a";
+const TRAILER: u8 = 0x3B;
+
+// Four colors need 2 bits per pixel, which is also the smallest minimum
+// code size GIF allows.
+const MIN_CODE_SIZE: u32 = 2;
+const CLEAR_CODE: u16 = 1 << MIN_CODE_SIZE;
+const END_CODE: u16 = CLEAR_CODE + 1;
+const MAX_CODES: u16 = 4096;
+
+const MIN_DELAY: u64 = 2;
+
+// Longest a single image may be shown, so its delay fits in a u16.
+const MAX_MERGED_FRAMES: u64 = 60 * 600;
+
+#[derive(Debug)]
+pub struct GifRecorder<W: Write> {
+    out: W,
+    width: usize,
+    height: usize,
+    // The frame waiting to be written and how many ticks it has lasted.
+    pending: Option<(Display, u64)>,
+    // Ticks already past `pending`.
+    frames: u64,
+    // Ticks covered by the images written so far, which trails `frames`
+    // after a skipped image.
+    written: u64,
+    // The first write error; recording stops there and `finish` reports it.
+    error: Option<io::Error>,
+}
+
+impl GifRecorder<BufWriter<File>> {
+    pub fn create(path: &Path, palette: &Palette, scale: usize) -> io::Result<GifRecorder<BufWriter<File>>> {
+        GifRecorder::new(BufWriter::new(File::create(path)?), palette, scale)
+    }
+}
+
+impl<W: Write> GifRecorder<W> {
+    // The image is `scale` times the 64x32 display, like the window, with
+    // high resolution pixels at half the size. Odd scales round up, so
+    // every high resolution pixel still gets at least one image pixel.
+    pub fn new(mut out: W, palette: &Palette, scale: usize) -> io::Result<GifRecorder<W>> {
+        let scale = scale.max(1).div_ceil(2);
+        let (width, height) = (HIRES_DISPLAY_WIDTH * scale, HIRES_DISPLAY_HEIGHT * scale);
+        out.write_all(HEADER)?;
+        out.write_all(&(width as u16).to_le_bytes())?;
+        out.write_all(&(height as u16).to_le_bytes())?;
+        // Global color table of 2^(1 + 1) entries, background color 0, no
+        // aspect ratio.
+        out.write_all(&[0x91, 0, 0])?;
+        for rgb in palette.0 {
+            out.write_all(&[rgb.r, rgb.g, rgb.b])?;
+        }
+        // Loop forever.
+        out.write_all(&[0x21, 0xFF, 0x0B])?;
+        out.write_all(b"NETSCAPE2.0")?;
+        out.write_all(&[0x03, 0x01, 0x00, 0x00, 0x00])?;
+        Ok(GifRecorder { out, width, height, pending: None, frames: 0, written: 0, error: None })
+    }
+
+    // Ticks recorded so far.
+    pub fn frames(&self) -> u64 {
+        self.frames + self.pending.as_ref().map_or(0, |(_, ticks)| *ticks)
+    }
+
+    // Records what `display` shows for one 60 Hz tick.
+    pub fn frame(&mut self, display: &Display) {
+        match &mut self.pending {
+            Some((pending, ticks)) if pending == display && *ticks < MAX_MERGED_FRAMES => *ticks += 1,
+            _ => {
+                self.flush_pending(false);
+                self.pending = Some((display.clone(), 1));
+            },
+        }
+    }
+
+    // Writes the last image and the trailer, and hands back the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_pending(true);
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.out.write_all(&[TRAILER])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    // The last image has nothing to hand its time to, so it is stretched to
+    // the minimum delay instead.
+    fn flush_pending(&mut self, last: bool) {
+        let Some((display, ticks)) = self.pending.take() else {
+            return;
+        };
+        self.frames += ticks;
+        let delay = centiseconds(self.frames) - centiseconds(self.written);
+        if delay < MIN_DELAY && !last {
+            return;
+        }
+        let delay = delay.max(MIN_DELAY);
+        self.written = self.frames;
+        if self.error.is_none() {
+            let pixels = self.render(&display);
+            if let Err(error) = self.write_image(&pixels, delay as u16) {
+                self.error = Some(error);
+            }
+        }
+    }
+
+    // Palette indices for the whole image, nearest neighbor scaled. Image
+    // rows that come from the same display row are copied.
+    fn render(&self, display: &Display) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(self.width * self.height);
+        let (low, high) = (display.plane(0), display.plane(1));
+        let mut previous = None;
+        for y in 0..self.height {
+            let row = y * display.height() / self.height;
+            if previous == Some(row) {
+                pixels.extend_from_within(pixels.len() - self.width..);
+                continue;
+            }
+            let (low, high) = (low[row], high[row]);
+            pixels.extend((0..self.width).map(|x| x * display.width() / self.width).map(|x| {
+                ((low << x) >> 127) as u8 | (((high << x) >> 127) as u8) << 1
+            }));
+            previous = Some(row);
+        }
+        pixels
+    }
+
+    fn write_image(&mut self, pixels: &[u8], delay: u16) -> io::Result<()> {
+        // Graphic control extension: leave the image in place, no
+        // transparency.
+        self.out.write_all(&[0x21, 0xF9, 0x04, 0x04])?;
+        self.out.write_all(&delay.to_le_bytes())?;
+        self.out.write_all(&[0x00, 0x00])?;
+        // Image descriptor for the whole screen, no local color table.
+        self.out.write_all(&[0x2C, 0, 0, 0, 0])?;
+        self.out.write_all(&(self.width as u16).to_le_bytes())?;
+        self.out.write_all(&(self.height as u16).to_le_bytes())?;
+        self.out.write_all(&[0x00, MIN_CODE_SIZE as u8])?;
+        // The data goes in sub-blocks of at most 255 bytes.
+        for block in lzw(pixels).chunks(255) {
+            self.out.write_all(&[block.len() as u8])?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_all(&[0x00])
+    }
+}
+
+// Time at the start of tick `frame`, in centiseconds.
+fn centiseconds(frame: u64) -> u64 {
+    (frame * 100 + 30) / 60
+}
+
+// GIF's variable code size LZW. Pixel values are 0-3, so each code's
+// extensions fit in a 4-entry row of `children`; 0 means no entry yet,
+// since no string gets a code below END_CODE.
+fn lzw(pixels: &[u8]) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+    let mut children = vec![[0u16; 4]; MAX_CODES as usize];
+    let mut size = MIN_CODE_SIZE + 1;
+    let mut next = END_CODE + 1;
+    bits.write(CLEAR_CODE, size);
+
+    let Some((&first, rest)) = pixels.split_first() else {
+        bits.write(END_CODE, size);
+        return bits.finish();
+    };
+    let mut prefix = first as u16;
+    for &pixel in rest {
+        let child = children[prefix as usize][pixel as usize];
+        if child != 0 {
+            prefix = child;
+            continue;
+        }
+        bits.write(prefix, size);
+        if next < MAX_CODES {
+            children[prefix as usize][pixel as usize] = next;
+            next += 1;
+            // The decoder adds each code one step later than we do, so it
+            // widens its codes when `next` is one past the limit here.
+            if next > 1 << size && size < 12 {
+                size += 1;
+            }
+        } else {
+            bits.write(CLEAR_CODE, size);
+            children.fill([0; 4]);
+            size = MIN_CODE_SIZE + 1;
+            next = END_CODE + 1;
+        }
+        prefix = pixel as u16;
+    }
+    bits.write(prefix, size);
+    // The decoder adds one more code after reading the last one, which
+    // may widen the end code.
+    if next < MAX_CODES && next + 1 > 1 << size && size < 12 {
+        size += 1;
+    }
+    bits.write(END_CODE, size);
+    bits.finish()
+}
+
+// Codes go in least significant bit first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u32) {
+        self.buffer |= (code as u32) << self.count;
+        self.count += size;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A plain GIF LZW decoder, written from the spec rather than from the
+    // encoder above.
+    fn unlzw(data: &[u8]) -> Vec<u8> {
+        let mut position = 0;
+        let mut read = |size: u32| -> u16 {
+            let code = (0..size).fold(0, |code, i| {
+                let bit = data[(position + i as usize) / 8] >> ((position + i as usize) % 8) & 1;
+                code | (bit as u16) << i
+            });
+            position += size as usize;
+            code
+        };
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let mut size = MIN_CODE_SIZE + 1;
+        let mut previous: Option<Vec<u8>> = None;
+        let mut out = Vec::new();
+        loop {
+            let code = read(size);
+            if code == CLEAR_CODE {
+                table = (0..CLEAR_CODE).map(|value| vec![value as u8]).chain([vec![], vec![]]).collect();
+                size = MIN_CODE_SIZE + 1;
+                previous = None;
+                continue;
+            }
+            if code == END_CODE {
+                return out;
+            }
+            let entry = match (table.get(code as usize), &previous) {
+                (Some(entry), _) => entry.clone(),
+                (None, Some(previous)) if code as usize == table.len() => [previous.as_slice(), &previous[..1]].concat(),
+                _ => panic!("invalid code {}", code),
+            };
+            out.extend_from_slice(&entry);
+            if let Some(previous) = previous {
+                if table.len() < MAX_CODES as usize {
+                    table.push([previous.as_slice(), &entry[..1]].concat());
+                }
+            }
+            if table.len() == 1 << size && size < 12 {
+                size += 1;
+            }
+            previous = Some(entry);
+        }
+    }
+
+    // (delay, pixels) for every image, checking the layout on the way.
+    fn images(gif: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        assert_eq!(gif[..6], *HEADER);
+        assert_eq!(*gif.last().unwrap(), TRAILER);
+        let u16_at = |at: usize| u16::from_le_bytes([gif[at], gif[at + 1]]);
+        let mut at = 13 + 3 * 4 + 19;
+        let mut images = Vec::new();
+        while gif[at] != TRAILER {
+            assert_eq!(gif[at..at + 4], [0x21, 0xF9, 0x04, 0x04]);
+            let delay = u16_at(at + 4);
+            at += 8;
+            assert_eq!(gif[at], 0x2C);
+            assert_eq!((u16_at(at + 5), u16_at(at + 7)), (u16_at(6), u16_at(8)));
+            at += 10;
+            assert_eq!(gif[at], MIN_CODE_SIZE as u8);
+            at += 1;
+            let mut data = Vec::new();
+            while gif[at] != 0 {
+                data.extend_from_slice(&gif[at + 1..at + 1 + gif[at] as usize]);
+                at += 1 + gif[at] as usize;
+            }
+            at += 1;
+            images.push((delay, unlzw(&data)));
+        }
+        images
+    }
+
+    #[test]
+    fn test_lzw_round_trips() {
+        let mut pixels: Vec<u8> = (0..20_000).map(|i| ((i * 7 + i / 13) % 4) as u8).collect();
+        pixels.extend(std::iter::repeat_n(2, 50_000));
+        for length in [0, 1, 2, 3, 5, 8, 100, 20_000, pixels.len()] {
+            assert_eq!(unlzw(&lzw(&pixels[..length])), pixels[..length]);
+        }
+        // Runs that end right as the code size grows.
+        for length in 1..600 {
+            let pixels: Vec<u8> = (0..length).map(|i| (i * 3 % 4) as u8).collect();
+            assert_eq!(unlzw(&lzw(&pixels)), pixels);
+        }
+    }
+
+    #[test]
+    fn test_frames_merge_with_exact_delays() {
+        let mut display = Display::default();
+        let mut recorder = GifRecorder::new(Vec::new(), &Palette::default(), 2).unwrap();
+        for _ in 0..3 {
+            recorder.frame(&display);
+        }
+        display.draw_row(0, 0, 0xF0, 8, 0b01, false);
+        recorder.frame(&display);
+        display.set_hires(true);
+        display.draw_row(1, 1, 0x80, 8, 0b11, false);
+        for _ in 0..56 {
+            recorder.frame(&display);
+        }
+        assert_eq!(recorder.frames(), 60);
+        let images = images(&recorder.finish().unwrap());
+
+        let delays: Vec<u16> = images.iter().map(|(delay, _)| *delay).collect();
+        assert_eq!(delays, [5, 2, 93]);
+        let pixel = |image: usize, x: usize, y: usize| images[image].1[y * 128 + x];
+        assert!(images[0].1.iter().all(|&pixel| pixel == 0));
+        assert_eq!((0..10).map(|x| pixel(1, x, 0)).collect::<Vec<u8>>(), [1, 1, 1, 1, 1, 1, 1, 1, 0, 0]);
+        assert_eq!((0..3).map(|x| pixel(1, x, 1)).collect::<Vec<u8>>(), [1, 1, 1]);
+        assert_eq!(pixel(1, 0, 2), 0);
+        // High resolution pixels are half the size.
+        assert_eq!((0..3).map(|x| pixel(2, x, 1)).collect::<Vec<u8>>(), [0, 3, 0]);
+        assert_eq!(pixel(2, 1, 2), 0);
+    }
+
+    #[test]
+    fn test_short_frames_fold_into_the_next() {
+        let mut display = Display::default();
+        let mut recorder = GifRecorder::new(Vec::new(), &Palette::default(), 1).unwrap();
+        // A different image every tick, as with flickering sprites.
+        for _ in 0..60 {
+            display.draw_row(0, 0, 0x80, 8, 0b01, false);
+            recorder.frame(&display);
+        }
+        assert_eq!(recorder.frames(), 60);
+        let images = images(&recorder.finish().unwrap());
+
+        let delays: Vec<u16> = images.iter().map(|(delay, _)| *delay).collect();
+        assert!(delays.iter().all(|&delay| delay >= 2), "{:?}", delays);
+        assert_eq!(delays.iter().map(|&delay| delay as u64).sum::<u64>(), 100);
+        assert_eq!(delays[..6], [2, 3, 2, 3, 2, 3]);
+        // Every third tick would get 1 centisecond and is skipped.
+        assert_eq!(images.len(), 40);
+    }
+
+    #[test]
+    fn test_hires_at_scale_one() {
+        let mut display = Display::default();
+        display.set_hires(true);
+        display.draw_row(0, 0, 0xA0, 8, 0b01, false);
+        display.draw_row(120, 63, 0x01, 8, 0b10, false);
+        let mut recorder = GifRecorder::new(Vec::new(), &Palette::default(), 1).unwrap();
+        recorder.frame(&display);
+        let gif = recorder.finish().unwrap();
+        assert_eq!(gif[6..10], [128, 0, 64, 0]);
+
+        let (_, pixels) = &images(&gif)[0];
+        assert_eq!(pixels[..4], [1, 0, 1, 0]);
+        assert_eq!(pixels[63 * 128 + 127], 2);
+        assert_eq!(pixels.iter().filter(|&&pixel| pixel != 0).count(), 3);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::display::Display;
+use crate::palette::Palette;
+use std::fmt::Write;
+
+// Text rendering of the display for terminals. Each character cell shows
+// two pixels stacked on top of each other as an upper half block, the top
+// one in the foreground color and the bottom one in the background color,
+// so 64x32 takes 64x16 cells and high resolution 128x32.
+//
+// Colors are 24-bit ANSI escapes. A cell only sets the colors that differ
+// from the cell before it, and every line ends by resetting them, so lines
+// can be printed anywhere on their own.
+const UPPER_HALF_BLOCK: char = '▀';
+const RESET: &str = "\x1b[0m";
+
+pub fn render(display: &Display, palette: &Palette) -> Vec<String> {
+    let (low, high) = (display.plane(0), display.plane(1));
+    (0..display.height())
+        .step_by(2)
+        .map(|y| {
+            let mut line = String::new();
+            let (mut foreground, mut background) = (None, None);
+            for x in 0..display.width() {
+                let pixel = |row: usize| ((low[row] << x) >> 127) as u8 | (((high[row] << x) >> 127) as u8) << 1;
+                let top = palette.color(pixel(y));
+                let bottom = palette.color(pixel(y + 1));
+                if background != Some(bottom) {
+                    write!(line, "\x1b[48;2;{};{};{}m", bottom.r, bottom.g, bottom.b).unwrap();
+                    background = Some(bottom);
+                }
+                // A cell in one color only needs the background.
+                if top == bottom {
+                    line.push(' ');
+                    continue;
+                }
+                if foreground != Some(top) {
+                    write!(line, "\x1b[38;2;{};{};{}m", top.r, top.g, top.b).unwrap();
+                    foreground = Some(top);
+                }
+                line.push(UPPER_HALF_BLOCK);
+            }
+            line.push_str(RESET);
+            line
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::palette::Rgb;
+
+    // `render` with the escapes taken out and colors shown as their palette
+    // index, for checking the layout by eye.
+    fn plain(line: &str, palette: &Palette) -> String {
+        let index = |rgb: Rgb| palette.0.iter().position(|&color| color == rgb).unwrap().to_string();
+        let mut out = String::new();
+        let (mut foreground, mut background) = (Rgb::new(0, 0, 0), Rgb::new(0, 0, 0));
+        let mut rest = line;
+        while let Some(c) = rest.chars().next() {
+            if let Some(escape) = rest.strip_prefix("\x1b[") {
+                let end = escape.find('m').unwrap();
+                let codes: Vec<u8> = escape[..end].split(';').map(|code| code.parse().unwrap()).collect();
+                match codes[..] {
+                    [38, 2, r, g, b] => foreground = Rgb::new(r, g, b),
+                    [48, 2, r, g, b] => background = Rgb::new(r, g, b),
+                    _ => {},
+                }
+                rest = &escape[end + 1..];
+                continue;
+            }
+            match c {
+                UPPER_HALF_BLOCK => out += &format!("{}{} ", index(foreground), index(background)),
+                _ => out += &format!("{}{} ", index(background), index(background)),
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+        out.trim_end().to_string()
+    }
+
+    #[test]
+    fn test_render() {
+        let mut display = Display::default();
+        // Pixel 0 lit on both rows, pixel 1 on the top row only and
+        // pixel 2 on the bottom row in the second plane.
+        display.draw_row(0, 0, 0b1100_0000, 8, 0b01, false);
+        display.draw_row(0, 1, 0b1000_0000, 8, 0b01, false);
+        display.draw_row(2, 1, 0b1000_0000, 8, 0b10, false);
+
+        let palette = Palette::default();
+        let lines = render(&display, &palette);
+        assert_eq!(lines.len(), 16);
+        assert!(lines.iter().all(|line| line.ends_with(RESET)));
+        assert_eq!(plain(&lines[0], &palette)[..14], *"11 10 02 00 00");
+        assert!(lines[0].starts_with("\x1b[48;2;0;255;0m \x1b[48;2;0;0;0m\x1b[38;2;0;255;0m▀"));
+        assert_eq!(lines[1], format!("\x1b[48;2;0;0;0m{}{}", " ".repeat(64), RESET));
+
+        display.set_hires(true);
+        let lines = render(&display, &palette);
+        assert_eq!(lines.len(), 32);
+        assert_eq!(plain(&lines[31], &palette), vec!["00"; 128].join(" "));
+    }
+}
@@ -0,0 +1,35 @@
+// Frontend-agnostic CHIP-8 core: keypad indices (0x0-0xF) go in through
+// `CHIP8::key_down`/`key_up` and a plain framebuffer comes out through
+// `CHIP8::display`. Nothing in here depends on SDL.
+pub mod assembler;
+pub mod audio;
+pub mod chip8;
+pub mod debugger;
+pub mod disasm;
+pub mod display;
+pub mod error;
+pub mod gdb;
+pub mod gif;
+pub mod halfblock;
+pub mod movie;
+pub mod palette;
+pub mod png;
+pub mod quirks;
+pub mod rewind;
+pub mod rng;
+pub mod savestate;
+pub mod timer;
+pub mod trace;
+pub mod types;
+
+pub use crate::audio::{AudioSink, NullSink, RecordingSink, SquareWave};
+pub use crate::chip8::{Instruction, CHIP8};
+pub use crate::debugger::Debugger;
+pub use crate::display::Display;
+pub use crate::error::{EmulationError, Fault};
+pub use crate::movie::Movie;
+pub use crate::palette::{Palette, Rgb};
+pub use crate::quirks::{Platform, Quirks};
+pub use crate::rewind::Rewind;
+pub use crate::rng::Rng;
+pub use crate::savestate::StateError;
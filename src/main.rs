@@ -1,84 +1,480 @@
-#![feature(bigint_helper_methods)]
-use sdl2::event::Event;
-use sdl2::keyboard::Scancode;
-use sdl2::pixels::Color;
-use std::path::Path;
-
-pub mod chip8;
-pub mod types;
-use crate::chip8::CHIP8;
-
-pub enum Kbd {
-    Scode(Scancode),
-    Quit,
-}
-
-fn get_scancode(event_pump: &mut sdl2::EventPump) -> Option<Kbd> {
-    for event in event_pump.poll_iter() {
-        match event {
-            Event::Quit { .. } => return Some(Kbd::Quit),
-            Event::KeyDown {
-                scancode: Some(scancode),
-                ..
-            } => {
-                return Some(Kbd::Scode(scancode));
+use chip8::audio::{parse_frequency, DEFAULT_BEEP_FREQUENCY, DEFAULT_VOLUME};
+use chip8::assembler::assemble;
+use chip8::chip8::{PROGRAM_MEMORY_START, XO_CHIP_MEMORY_SIZE};
+use chip8::disasm::disassemble;
+use chip8::gdb::GdbStub;
+use chip8::gif::GifRecorder;
+use chip8::movie::apply_keys;
+use chip8::trace::{parse_address_range, parse_cycle_range, Tracer};
+use chip8::{AudioSink, Display, Movie, NullSink, Palette, Platform, Rgb, Rng, CHIP8};
+use clap::{Parser, Subcommand};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::net::TcpListener;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+#[cfg(feature = "sdl")]
+mod sdl;
+#[cfg(feature = "tui")]
+mod tui;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "A small, barebones CHIP-8 emulator.")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// ROM file to load at 0x200.
+    #[arg(required = true)]
+    rom: Option<PathBuf>,
+
+    /// Instructions executed per 60 Hz frame.
+    #[arg(short, long = "ipf", default_value_t = 5)]
+    instructions_per_frame: usize,
+
+    /// Window size as a multiple of the 64x32 display.
+    #[arg(short, long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..=64))]
+    scale: u32,
+
+    /// Color of lit pixels, as RRGGBB.
+    #[arg(long, default_value = "00FF00")]
+    fg: Rgb,
+
+    /// Color of unlit pixels, as RRGGBB.
+    #[arg(long, default_value = "000000")]
+    bg: Rgb,
+
+    /// Quirk and memory preset: vip, chip48, schip or xochip.
+    #[arg(short, long, default_value = "schip")]
+    platform: Platform,
+
+    /// Pitch of the beeper in Hz.
+    #[arg(long, value_name = "HZ", default_value_t = DEFAULT_BEEP_FREQUENCY, value_parser = parse_frequency)]
+    beep: f32,
+
+    /// Beeper volume, from 0.0 to 1.0.
+    #[arg(long, default_value_t = DEFAULT_VOLUME)]
+    volume: f32,
+
+    /// Memory kept for rewinding with Backspace, in MiB. 0 turns it off.
+    #[arg(long, value_name = "MIB", default_value_t = 16)]
+    rewind: usize,
+
+    /// Start paused with a debugger prompt on the terminal.
+    #[arg(short, long)]
+    debug: bool,
+
+    /// Seed for CXNN's random numbers, to make a run repeatable.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Run this many frames without a window, then print the display.
+    #[arg(long, value_name = "FRAMES")]
+    headless: Option<u64>,
+
+    /// Write a line per executed instruction to this file.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["debug", "gdb"])]
+    trace: Option<PathBuf>,
+
+    /// Only trace instructions at these addresses, as START-END in hex.
+    #[arg(long, value_name = "RANGE", requires = "trace", value_parser = parse_address_range)]
+    trace_pc: Option<RangeInclusive<u16>>,
+
+    /// Only trace these cycles, as START-END or START-.
+    #[arg(long, value_name = "RANGE", requires = "trace", value_parser = parse_cycle_range)]
+    trace_cycles: Option<RangeInclusive<u64>>,
+
+    /// Record the keypad to a movie file, to replay the session later.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["debug", "gdb"])]
+    record: Option<PathBuf>,
+
+    /// Play back a movie recorded with --record, reporting where it diverges.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["debug", "gdb", "record"])]
+    play: Option<PathBuf>,
+
+    /// Record the screen to an animated GIF until the emulator exits.
+    #[arg(long, value_name = "FILE")]
+    gif: Option<PathBuf>,
+
+    /// Play in the terminal instead of a window, for sessions over SSH.
+    #[arg(long, conflicts_with_all = ["debug", "gdb", "headless"])]
+    tui: bool,
+
+    /// Wait for GDB to connect on this localhost port before starting.
+    #[arg(long, value_name = "PORT", conflicts_with_all = ["debug", "headless"])]
+    gdb: Option<u16>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print a listing of a ROM, telling code apart from data.
+    Disasm {
+        /// ROM file to disassemble.
+        rom: PathBuf,
+    },
+    /// Assemble Octo source into a ROM, with a symbol file next to it.
+    Asm {
+        /// Octo source file.
+        source: PathBuf,
+
+        /// Where to write the ROM. Defaults to the source with a .ch8 extension.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Where to write the symbols. Defaults to the ROM with a .sym extension.
+        #[arg(long)]
+        symbols: Option<PathBuf>,
+    },
+    /// Replay a movie without a window and check every frame against it.
+    Replay {
+        /// Movie recorded with --record.
+        movie: PathBuf,
+
+        /// The ROM it was recorded with.
+        rom: PathBuf,
+    },
+}
+
+impl Args {
+    // Only missing when a subcommand runs instead of the emulator.
+    pub fn rom(&self) -> &Path {
+        self.rom.as_deref().expect("clap requires a ROM without a subcommand")
+    }
+
+    pub fn palette(&self) -> Palette {
+        Palette::with_colors(self.bg, self.fg)
+    }
+}
+
+pub type FileTracer = Tracer<BufWriter<File>>;
+
+fn open_tracer(args: &Args) -> std::io::Result<Option<FileTracer>> {
+    let Some(path) = &args.trace else {
+        return Ok(None);
+    };
+    let mut tracer = Tracer::create(path)?;
+    if let Some(range) = &args.trace_pc {
+        tracer = tracer.with_addresses(range.clone());
+    }
+    if let Some(range) = &args.trace_cycles {
+        tracer = tracer.with_cycles(range.clone());
+    }
+    Ok(Some(tracer))
+}
+
+// Flushes the trace; a failed write only shows up here.
+pub fn close_tracer(tracer: Option<FileTracer>) {
+    if let Some(Err(error)) = tracer.map(Tracer::finish) {
+        eprintln!("Could not write the trace: {}", error);
+    }
+}
+
+// A GIF being recorded, one frame per 60 Hz tick.
+pub struct GifSession {
+    path: PathBuf,
+    recorder: GifRecorder<BufWriter<File>>,
+}
+
+impl GifSession {
+    pub fn create(path: PathBuf, args: &Args) -> std::io::Result<GifSession> {
+        let recorder = GifRecorder::create(&path, &args.palette(), args.scale as usize)?;
+        Ok(GifSession { path, recorder })
+    }
+
+    pub fn frame(&mut self, display: &Display) {
+        self.recorder.frame(display);
+    }
+
+    pub fn finish(self) {
+        let frames = self.recorder.frames();
+        match self.recorder.finish() {
+            Ok(_) => eprintln!("Recorded {} frames to {}", frames, self.path.display()),
+            Err(error) => eprintln!("Could not write {}: {}", self.path.display(), error),
+        }
+    }
+}
+
+// A movie being recorded or played back, one frame at a time.
+pub struct MovieSession {
+    movie: Movie,
+    // Where the recording goes; `None` when playing back.
+    record_to: Option<PathBuf>,
+    frame: usize,
+    diverged: bool,
+}
+
+impl MovieSession {
+    pub fn instructions_per_frame(&self) -> usize {
+        self.movie.instructions_per_frame
+    }
+
+    // Keys for the next frame: the recorded ones while playing back,
+    // whatever is `held` while recording or once the movie is over.
+    pub fn keys(&self, held: u16) -> u16 {
+        match self.movie.frames.get(self.frame) {
+            Some(frame) if self.record_to.is_none() => frame.keys,
+            _ => held,
+        }
+    }
+
+    pub fn after_frame(&mut self, keys: u16, chip: &CHIP8) {
+        if self.record_to.is_some() {
+            self.movie.record_frame(keys, chip);
+        } else if self.frame < self.movie.frames.len() {
+            if !self.diverged && !self.movie.matches(self.frame, chip) {
+                self.diverged = true;
+                eprintln!("Playback diverges from the movie at frame {}", self.frame);
+            }
+            if self.frame + 1 == self.movie.frames.len() {
+                eprintln!("Movie finished after {} frames", self.movie.frames.len());
+            }
+        }
+        self.frame += 1;
+    }
+
+    pub fn finish(self) {
+        if let Some(path) = &self.record_to {
+            match self.movie.save(path) {
+                Ok(()) => eprintln!("Recorded {} frames to {}", self.movie.frames.len(), path.display()),
+                Err(error) => eprintln!("Could not write {}: {}", path.display(), error),
             }
-            _ => continue,
         }
     }
-    None
 }
 
-fn main() {
-    let mut chip = CHIP8::default();
+// Sets up the machine, from the movie when playing one back so the
+// platform, speed and seed are the recorded ones.
+fn start(args: &Args) -> Result<(CHIP8, Option<MovieSession>), String> {
+    let rom = fs::read(args.rom()).map_err(|error| format!("Could not load {}: {}", args.rom().display(), error))?;
+    if let Some(path) = &args.play {
+        let movie = Movie::load(path).map_err(|error| format!("Could not load {}: {}", path.display(), error))?;
+        let chip = movie.start(&rom).map_err(|error| format!("Could not play {}: {}", path.display(), error))?;
+        return Ok((chip, Some(MovieSession { movie, record_to: None, frame: 0, diverged: false })));
+    }
+
+    let seed = args.seed.unwrap_or_else(rand::random);
+    let mut chip = CHIP8::new(args.platform);
+    chip.set_rng(Rng::new(seed));
     chip.load_font();
-    let path = Path::new("./resources/ibm_logo.ch8");
-    chip.load_from_file(&path);
-
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
-
-    let window = video_subsystem
-        .window(
-            "CHIP-8",
-            (chip8::DISPLAY_WIDTH * 10) as u32,
-            (chip8::DISPLAY_HEIGHT * 10) as u32,
-        )
-        .position_centered()
-        .build()
-        .unwrap();
-
-    let mut canvas = window.into_canvas().build().unwrap();
-    canvas.set_logical_size(64, 32).unwrap();
-
-    let mut events = sdl_context.event_pump().unwrap();
-
-    'main: loop {
-        canvas.set_draw_color(Color::BLACK);
-        canvas.clear();
-
-        let instruction = chip.fetch();
-
-        let scancode: Option<Kbd> = get_scancode(&mut events);
-        match scancode {
-            Some(Kbd::Quit) => break 'main,
-            Some(Kbd::Scode(polled_scode)) => {
-                chip.handle_keydown(Some(polled_scode));
-            }
-            None => {}
+    chip.load_from_slice(&rom, None).map_err(|error| format!("Could not load {}: {}", args.rom().display(), error))?;
+    let session = args.record.as_ref().map(|path| MovieSession {
+        movie: Movie::new(&rom, args.platform, args.instructions_per_frame, seed),
+        record_to: Some(path.clone()),
+        frame: 0,
+        diverged: false,
+    });
+    Ok((chip, session))
+}
+
+fn run_headless(
+    mut chip: CHIP8,
+    args: &Args,
+    frames: u64,
+    audio: &mut dyn AudioSink,
+    mut tracer: Option<FileTracer>,
+    mut movie: Option<MovieSession>,
+    mut gif: Option<GifSession>,
+) -> ExitCode {
+    let instructions = movie.as_ref().map_or(args.instructions_per_frame, MovieSession::instructions_per_frame);
+    let mut fault = None;
+    for _ in 0..frames {
+        let keys = movie.as_ref().map(|movie| movie.keys(0));
+        if let Some(keys) = keys {
+            apply_keys(&mut chip, keys);
+        }
+        let result = match tracer.as_mut() {
+            Some(tracer) => tracer.run_frame(&mut chip, instructions),
+            None => chip.run_frame(instructions),
+        };
+        if let Err(error) = result {
+            fault = Some(error);
+            break;
+        }
+        if let (Some(movie), Some(keys)) = (movie.as_mut(), keys) {
+            movie.after_frame(keys, &chip);
+        }
+        if let Some(gif) = gif.as_mut() {
+            gif.frame(chip.display());
+        }
+        audio.set_tone(chip.is_sound_playing());
+    }
+    print!("{}", chip.display());
+    close_tracer(tracer);
+    if let Some(movie) = movie {
+        movie.finish();
+    }
+    if let Some(gif) = gif {
+        gif.finish();
+    }
+    match fault {
+        Some(error) => {
+            eprintln!("CHIP-8 fault: {}", error);
+            ExitCode::FAILURE
+        }
+        None => ExitCode::SUCCESS,
+    }
+}
+
+fn wait_for_gdb(port: u16) -> std::io::Result<GdbStub> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("Waiting for GDB on 127.0.0.1:{}", port);
+    GdbStub::accept(&listener)
+}
+
+fn disasm(rom: &Path) -> ExitCode {
+    match fs::read(rom) {
+        Ok(bytes) if PROGRAM_MEMORY_START + bytes.len() > XO_CHIP_MEMORY_SIZE => {
+            eprintln!("Could not read {}: ROM does not fit in memory", rom.display());
+            ExitCode::FAILURE
+        }
+        Ok(bytes) => {
+            print!("{}", disassemble(&bytes));
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("Could not read {}: {}", rom.display(), error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn replay(movie_path: &Path, rom_path: &Path) -> ExitCode {
+    let loaded = Movie::load(movie_path)
+        .map_err(|error| format!("Could not load {}: {}", movie_path.display(), error))
+        .and_then(|movie| Ok((movie, fs::read(rom_path).map_err(|error| format!("Could not read {}: {}", rom_path.display(), error))?)));
+    let (movie, rom) = match loaded {
+        Ok(loaded) => loaded,
+        Err(message) => {
+            eprintln!("{}", message);
+            return ExitCode::FAILURE;
+        }
+    };
+    match movie.verify(&rom) {
+        Ok(None) => {
+            println!("All {} frames match", movie.frames.len());
+            ExitCode::SUCCESS
+        }
+        Ok(Some(frame)) => {
+            println!("Diverges at frame {} of {}", frame, movie.frames.len());
+            ExitCode::FAILURE
+        }
+        Err(error) => {
+            eprintln!("Could not replay {}: {}", movie_path.display(), error);
+            ExitCode::FAILURE
         }
+    }
+}
 
-        // println!("{:?}", instruction);
+fn asm(source: &Path, output: Option<&Path>, symbols: Option<&Path>) -> ExitCode {
+    let text = match fs::read_to_string(source) {
+        Ok(text) => text,
+        Err(error) => {
+            eprintln!("Could not read {}: {}", source.display(), error);
+            return ExitCode::FAILURE;
+        }
+    };
+    let assembly = match assemble(&text) {
+        Ok(assembly) => assembly,
+        Err(error) => {
+            eprintln!("{}:{}", source.display(), error);
+            return ExitCode::FAILURE;
+        }
+    };
 
-        chip.execute(instruction);
+    let output = output.map_or_else(|| source.with_extension("ch8"), Path::to_path_buf);
+    let symbols = symbols.map_or_else(|| output.with_extension("sym"), Path::to_path_buf);
+    for (path, contents) in [(&output, assembly.rom.as_slice()), (&symbols, assembly.symbol_file().as_bytes())] {
+        if let Err(error) = fs::write(path, contents) {
+            eprintln!("Could not write {}: {}", path.display(), error);
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
 
-        canvas.set_draw_color(Color::GREEN);
-        canvas
-            .draw_points(chip.get_pixels_to_draw().as_slice())
-            .unwrap();
+fn main() -> ExitCode {
+    let args = Args::parse();
 
-        canvas.present();
+    match &args.command {
+        Some(Command::Disasm { rom }) => return disasm(rom),
+        Some(Command::Asm { source, output, symbols }) => return asm(source, output.as_deref(), symbols.as_deref()),
+        Some(Command::Replay { movie, rom }) => return replay(movie, rom),
+        None => {}
+    }
 
-        std::thread::sleep(std::time::Duration::new(0, 1_000_000_000u32 / 300));
+    let (chip, movie) = match start(&args) {
+        Ok(started) => started,
+        Err(message) => {
+            eprintln!("{}", message);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let tracer = match open_tracer(&args) {
+        Ok(tracer) => tracer,
+        Err(error) => {
+            eprintln!("Could not create the trace file: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let gif = match args.gif.clone().map(|path| GifSession::create(path, &args)).transpose() {
+        Ok(gif) => gif,
+        Err(error) => {
+            eprintln!("Could not create the GIF: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Some(frames) = args.headless {
+        return run_headless(chip, &args, frames, &mut NullSink, tracer, movie, gif);
+    }
+
+    if args.tui {
+        #[cfg(feature = "tui")]
+        return match tui::run(chip, &args, tracer, movie, gif) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(error) => {
+                eprintln!("Terminal error: {}", error);
+                ExitCode::FAILURE
+            }
+        };
+        #[cfg(not(feature = "tui"))]
+        {
+            eprintln!("chip8 was built without the `tui` feature, rebuild with --features tui.");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let gdb = match args.gdb.map(wait_for_gdb).transpose() {
+        Ok(gdb) => gdb,
+        Err(error) => {
+            eprintln!("Could not listen for GDB: {}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    #[cfg(feature = "sdl")]
+    {
+        sdl::run(chip, &args, gdb, tracer, movie, gif);
+        ExitCode::SUCCESS
+    }
+
+    // Without a window GDB can still drive the machine; the display is
+    // printed once it lets go.
+    #[cfg(not(feature = "sdl"))]
+    {
+        let _ = (tracer, movie, gif);
+        if let Some(mut gdb) = gdb {
+            let mut chip = chip;
+            gdb.run(&mut chip, args.instructions_per_frame);
+            print!("{}", chip.display());
+            return ExitCode::SUCCESS;
+        }
+        eprintln!("chip8 was built without the `sdl` feature, only --headless and --gdb are available.");
+        ExitCode::FAILURE
     }
 }
@@ -0,0 +1,287 @@
+use crate::chip8::{CHIP8, KEYPAD_SIZE};
+use crate::display::Display;
+use crate::error::EmulationError;
+use crate::quirks::Platform;
+use crate::rng::Rng;
+use std::fmt;
+use std::fs::{read_to_string, write};
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+// Input movie: everything needed to replay a session from power on. The
+// file is plain text so it can be attached to a ticket and read there:
+//
+//   chip8 movie 1
+//   rom 9c2bb7a1d0e4f6c3
+//   platform schip
+//   ipf 5
+//   seed 42
+//   0000 4f1c0be3a5d09e12
+//   0010 4f1c0be3a5d09e12
+//   ...
+//
+// rom is the FNV-1a hash of the ROM file. Each frame line holds the keys
+// held during that frame, bit n for key n, and the FNV-1a hash of the
+// framebuffer after it. Replaying sets the keypad from the movie before
+// each frame and compares hashes after it, so the first frame that comes
+// out differently is known exactly.
+pub const MOVIE_HEADER: &str = "chip8 movie 1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub keys: u16,
+    pub display_hash: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: u64,
+    pub platform: Platform,
+    pub instructions_per_frame: usize,
+    pub seed: u64,
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MovieError {
+    Parse { line: usize, message: String },
+    RomMismatch,
+    RomTooLarge,
+    Fault { frame: usize, error: EmulationError },
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            MovieError::RomMismatch => write!(f, "the movie was recorded with a different ROM"),
+            MovieError::RomTooLarge => write!(f, "the ROM does not fit in the movie's platform memory"),
+            MovieError::Fault { frame, error } => write!(f, "fault in frame {}: {}", frame, error),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl Movie {
+    pub fn new(rom: &[u8], platform: Platform, instructions_per_frame: usize, seed: u64) -> Movie {
+        Movie {
+            rom_hash: fnv1a(rom),
+            platform,
+            instructions_per_frame,
+            seed,
+            frames: Vec::new(),
+        }
+    }
+
+    // The machine at power on, the same for recording and playback.
+    pub fn start(&self, rom: &[u8]) -> Result<CHIP8, MovieError> {
+        if fnv1a(rom) != self.rom_hash {
+            return Err(MovieError::RomMismatch);
+        }
+        let mut chip = CHIP8::new(self.platform);
+        chip.set_rng(Rng::new(self.seed));
+        chip.load_font();
+        chip.load_from_slice(rom, None).map_err(|_| MovieError::RomTooLarge)?;
+        Ok(chip)
+    }
+
+    // Call after running a frame with `keys` applied.
+    pub fn record_frame(&mut self, keys: u16, chip: &CHIP8) {
+        self.frames.push(Frame { keys, display_hash: display_hash(chip.display()) });
+    }
+
+    // Whether `chip` looks the way it did after `frame` was recorded.
+    pub fn matches(&self, frame: usize, chip: &CHIP8) -> bool {
+        self.frames.get(frame).is_some_and(|recorded| recorded.display_hash == display_hash(chip.display()))
+    }
+
+    // Replays the whole movie without a window. Returns the first frame
+    // whose framebuffer differs from the recording, or `None` if every
+    // one matches.
+    pub fn verify(&self, rom: &[u8]) -> Result<Option<usize>, MovieError> {
+        let mut chip = self.start(rom)?;
+        for (number, frame) in self.frames.iter().enumerate() {
+            apply_keys(&mut chip, frame.keys);
+            chip.run_frame(self.instructions_per_frame).map_err(|error| MovieError::Fault { frame: number, error })?;
+            if !self.matches(number, &chip) {
+                return Ok(Some(number));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        write(path, self.to_string())
+    }
+
+    pub fn load(path: &Path) -> io::Result<Movie> {
+        read_to_string(path)?.parse().map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", MOVIE_HEADER)?;
+        writeln!(f, "rom {:016x}", self.rom_hash)?;
+        writeln!(f, "platform {}", self.platform)?;
+        writeln!(f, "ipf {}", self.instructions_per_frame)?;
+        writeln!(f, "seed {}", self.seed)?;
+        for frame in &self.frames {
+            writeln!(f, "{:04x} {:016x}", frame.keys, frame.display_hash)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Movie {
+    type Err = MovieError;
+
+    fn from_str(s: &str) -> Result<Movie, MovieError> {
+        let mut lines = s.lines().enumerate().map(|(number, line)| (number + 1, line.trim()));
+        let error = |line: usize, message: &str| MovieError::Parse { line, message: message.to_string() };
+
+        match lines.next() {
+            Some((_, MOVIE_HEADER)) => {},
+            _ => return Err(error(1, "not a chip8 movie")),
+        }
+        let mut field = |name: &str| -> Result<(usize, String), MovieError> {
+            let (number, line) = lines.next().ok_or_else(|| error(0, "movie is truncated"))?;
+            match line.split_once(' ') {
+                Some((key, value)) if key == name => Ok((number, value.to_string())),
+                _ => Err(error(number, &format!("expected {}", name))),
+            }
+        };
+        let (number, rom) = field("rom")?;
+        let rom_hash = u64::from_str_radix(&rom, 16).map_err(|_| error(number, "invalid ROM hash"))?;
+        let (number, platform) = field("platform")?;
+        let platform = platform.parse().map_err(|message: String| error(number, &message))?;
+        let (number, ipf) = field("ipf")?;
+        let instructions_per_frame = ipf.parse().map_err(|_| error(number, "invalid instructions per frame"))?;
+        let (number, seed) = field("seed")?;
+        let seed = seed.parse().map_err(|_| error(number, "invalid seed"))?;
+
+        let frames = lines
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(number, line)| {
+                let (keys, hash) = line.split_once(' ').ok_or_else(|| error(number, "expected keys and a hash"))?;
+                Ok(Frame {
+                    keys: u16::from_str_radix(keys, 16).map_err(|_| error(number, "invalid keys"))?,
+                    display_hash: u64::from_str_radix(hash, 16).map_err(|_| error(number, "invalid hash"))?,
+                })
+            })
+            .collect::<Result<Vec<Frame>, MovieError>>()?;
+        Ok(Movie { rom_hash, platform, instructions_per_frame, seed, frames })
+    }
+}
+
+// Presses and releases keys until the keypad matches `keys`, bit n for
+// key n, just as a frontend would on key events.
+pub fn apply_keys(chip: &mut CHIP8, keys: u16) {
+    for key in 0..KEYPAD_SIZE as u8 {
+        match (keys & 1 << key != 0, chip.is_key_pressed(key)) {
+            (true, false) => chip.key_down(key),
+            (false, true) => chip.key_up(key),
+            _ => {},
+        }
+    }
+}
+
+// Hashes the width, the height and then one byte per pixel value, row by
+// row, shifting the pixels straight out of the planes.
+pub fn display_hash(display: &Display) -> u64 {
+    let width = display.width();
+    let mut bytes = Vec::with_capacity(2 + width * display.height());
+    bytes.extend_from_slice(&[width as u8, display.height() as u8]);
+    for (&low, &high) in display.plane(0).iter().zip(display.plane(1)) {
+        bytes.extend((0..width).map(|x| ((low << x) >> 127) as u8 | (((high << x) >> 127) as u8) << 1));
+    }
+    fnv1a(&bytes)
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Draws the digit of the key held, at a random spot, every frame.
+    const ROM: &[u8] = &[
+        0x00, 0xE0, // 200: CLS
+        0xF0, 0x0A, // 202: V0 = key
+        0xF0, 0x29, // 204: I = font(V0)
+        0xC1, 0x3F, // 206: V1 = random & 0x3F
+        0xC2, 0x1F, // 208: V2 = random & 0x1F
+        0xD1, 0x25, // 20A: draw
+        0x12, 0x02, // 20C: loop
+    ];
+
+    fn record(keys: &[u16]) -> Movie {
+        let mut movie = Movie::new(ROM, Platform::SuperChip, 20, 1234);
+        let mut chip = movie.start(ROM).unwrap();
+        for &held in keys {
+            apply_keys(&mut chip, held);
+            chip.run_frame(movie.instructions_per_frame).unwrap();
+            movie.record_frame(held, &chip);
+        }
+        movie
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        assert_eq!(fnv1a(b""), 0xCBF2_9CE4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xAF63_DC4C_8601_EC8C);
+    }
+
+    // Recorded movies must keep verifying, so the hash stays over the same
+    // bytes: the size and then every pixel value row by row.
+    #[test]
+    fn test_display_hash_layout() {
+        let mut display = Display::default();
+        for hires in [false, true] {
+            display.set_hires(hires);
+            display.draw_row(0, 0, 0xA5, 8, 0b01, false);
+            display.draw_row(60, 5, 0xFF, 8, 0b10, false);
+            display.draw_row(3, 31, 0xC3, 8, 0b11, false);
+            let mut bytes = vec![display.width() as u8, display.height() as u8];
+            bytes.extend(display.rows().flatten());
+            assert_eq!(display_hash(&display), fnv1a(&bytes));
+        }
+    }
+
+    #[test]
+    fn test_replay_matches() {
+        let movie = record(&[0, 0x0020, 0, 0, 0x0100, 0x0100, 0]);
+        assert_eq!(movie.verify(ROM), Ok(None));
+
+        let text = movie.to_string();
+        assert!(text.starts_with("chip8 movie 1\nrom "), "{}", text);
+        assert_eq!(text.parse::<Movie>(), Ok(movie));
+    }
+
+    #[test]
+    fn test_divergence() {
+        let mut movie = record(&[0, 0x0020, 0, 0, 0x0100, 0x0100, 0]);
+        movie.frames[4].keys = 0x0200;
+        assert_eq!(movie.verify(ROM), Ok(Some(5)));
+
+        let mut movie = record(&[0, 0x0020, 0, 0]);
+        movie.seed += 1;
+        assert_eq!(movie.verify(ROM), Ok(Some(2)));
+        assert_eq!(movie.verify(&ROM[..12]), Err(MovieError::RomMismatch));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text: &str| match text.parse::<Movie>() {
+            Err(MovieError::Parse { line, .. }) => line,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(error("chip8 movie 2\n"), 1);
+        assert_eq!(error("chip8 movie 1\nrom 00\nplatform gameboy\n"), 3);
+        assert_eq!(error("chip8 movie 1\nrom 00\nplatform vip\nipf 5\nseed 1\n0000 00\nzz 00\n"), 7);
+    }
+}
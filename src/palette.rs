@@ -0,0 +1,70 @@
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Rgb {
+        Rgb { r, g, b }
+    }
+}
+
+// Accepts "RRGGBB", optionally prefixed with '#' or "0x".
+impl FromStr for Rgb {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Rgb, String> {
+        let hex = s.trim_start_matches('#').trim_start_matches("0x");
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("invalid color '{}', expected RRGGBB", s));
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+        Ok(Rgb::new(channel(0), channel(2), channel(4)))
+    }
+}
+
+// One color per pixel value: background, plane 1, plane 2 and both planes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette(pub [Rgb; 4]);
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette([
+            Rgb::new(0x00, 0x00, 0x00),
+            Rgb::new(0x00, 0xFF, 0x00),
+            Rgb::new(0xFF, 0x55, 0x00),
+            Rgb::new(0xFF, 0xFF, 0x55),
+        ])
+    }
+}
+
+impl Palette {
+    pub fn with_colors(background: Rgb, foreground: Rgb) -> Palette {
+        let mut palette = Palette::default();
+        palette.0[0] = background;
+        palette.0[1] = foreground;
+        palette
+    }
+
+    pub fn color(&self, pixel: u8) -> Rgb {
+        self.0[(pixel & 0b11) as usize]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_rgb() {
+        assert_eq!("#1A2B3C".parse::<Rgb>(), Ok(Rgb::new(0x1A, 0x2B, 0x3C)));
+        assert_eq!("ff8000".parse::<Rgb>(), Ok(Rgb::new(0xFF, 0x80, 0x00)));
+        assert_eq!("0x000001".parse::<Rgb>(), Ok(Rgb::new(0, 0, 1)));
+        assert!("#12345".parse::<Rgb>().is_err());
+        assert!("green".parse::<Rgb>().is_err());
+    }
+}
@@ -0,0 +1,306 @@
+use crate::display::Display;
+use crate::palette::Palette;
+use std::fs::write;
+use std::io;
+use std::path::Path;
+
+// Just enough PNG to write screenshots: 8-bit indexed color with the
+// palette as PLTE, every scanline unfiltered, and the zlib stream packed
+// with fixed Huffman codes. Scaled framebuffers are long runs of one pixel
+// value and rows that repeat the one above, so LZ77 matches against the
+// previous byte and the previous row get nearly all of the compression a
+// full deflate implementation would.
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+// Color type 3 is indexed color.
+const COLOR_TYPE_INDEXED: u8 = 3;
+
+// Deflate can't match fewer than 3 bytes or more than 258.
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+// The framebuffer with each pixel blown up to a `scale` by `scale` square.
+pub fn encode_display(display: &Display, palette: &Palette, scale: usize) -> Vec<u8> {
+    let scale = scale.max(1);
+    let (width, height) = (display.width() * scale, display.height() * scale);
+    let mut pixels = Vec::with_capacity(width * height);
+    for (&low, &high) in display.plane(0).iter().zip(display.plane(1)) {
+        let start = pixels.len();
+        pixels.extend((0..display.width()).flat_map(|x| {
+            let pixel = ((low << x) >> 127) as u8 | (((high << x) >> 127) as u8) << 1;
+            std::iter::repeat_n(pixel, scale)
+        }));
+        for _ in 1..scale {
+            pixels.extend_from_within(start..start + width);
+        }
+    }
+    encode_indexed(width as u32, height as u32, palette, &pixels)
+}
+
+pub fn save_display(path: &Path, display: &Display, palette: &Palette, scale: usize) -> io::Result<()> {
+    write(path, encode_display(display, palette, scale))
+}
+
+// `pixels` holds one palette index per pixel, row by row. PNG has no
+// empty images, so both dimensions must be at least 1.
+pub fn encode_indexed(width: u32, height: u32, palette: &Palette, pixels: &[u8]) -> Vec<u8> {
+    assert!(width > 0 && height > 0, "PNG images can't be empty");
+    assert_eq!(pixels.len(), width as usize * height as usize, "pixel count doesn't match the image size");
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Bit depth, color type, compression, filter and interlace methods.
+    header.extend_from_slice(&[8, COLOR_TYPE_INDEXED, 0, 0, 0]);
+
+    let colors: Vec<u8> = palette.0.iter().flat_map(|rgb| [rgb.r, rgb.g, rgb.b]).collect();
+
+    // Each scanline starts with its filter type, 0 for none.
+    let mut scanlines = Vec::with_capacity(pixels.len() + height as usize);
+    for row in pixels.chunks(width as usize) {
+        scanlines.push(0);
+        scanlines.extend_from_slice(row);
+    }
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"PLTE", &colors);
+    write_chunk(&mut png, b"IDAT", &zlib(&scanlines, width as usize + 1));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// A zlib stream holding a single fixed Huffman deflate block. Matches are
+// only looked for one byte back and `stride` bytes back, the row above.
+fn zlib(data: &[u8], stride: usize) -> Vec<u8> {
+    // Deflate with a 32K window, no preset dictionary, fastest level.
+    let mut bits = BitWriter { bytes: vec![0x78, 0x01], buffer: 0, count: 0 };
+    // BFINAL, then BTYPE 01 for fixed codes.
+    bits.write(1, 1);
+    bits.write(1, 2);
+
+    let mut position = 0;
+    while position < data.len() {
+        let (length, distance) = [1, stride]
+            .iter()
+            .filter(|&&distance| distance <= position && distance <= 32768)
+            .map(|&distance| (match_length(data, position, distance), distance))
+            .max_by_key(|&(length, _)| length)
+            .unwrap_or((0, 0));
+        if length >= MIN_MATCH {
+            bits.write_match(length, distance);
+            position += length;
+        } else {
+            bits.write_literal(data[position] as u16);
+            position += 1;
+        }
+    }
+    bits.write_literal(256);
+    bits.flush();
+
+    let mut stream = bits.bytes;
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+fn match_length(data: &[u8], position: usize, distance: usize) -> usize {
+    data[position..].iter().take(MAX_MATCH).zip(&data[position - distance..]).take_while(|(a, b)| a == b).count()
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    // Plain values go in least significant bit first.
+    fn write(&mut self, value: u32, bits: u32) {
+        self.buffer |= value << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes go in most significant bit first.
+    fn write_code(&mut self, code: u32, bits: u32) {
+        self.write(code.reverse_bits() >> (32 - bits), bits);
+    }
+
+    fn write_literal(&mut self, symbol: u16) {
+        match symbol {
+            0..=143 => self.write_code(0x30 + symbol as u32, 8),
+            144..=255 => self.write_code(0x190 + (symbol as u32 - 144), 9),
+            256..=279 => self.write_code(symbol as u32 - 256, 7),
+            _ => self.write_code(0xC0 + (symbol as u32 - 280), 8),
+        }
+    }
+
+    fn write_match(&mut self, length: usize, distance: usize) {
+        let code = LENGTH_BASES.iter().rposition(|&base| base as usize <= length).unwrap();
+        self.write_literal(257 + code as u16);
+        self.write((length - LENGTH_BASES[code] as usize) as u32, LENGTH_EXTRA_BITS[code] as u32);
+
+        let code = DISTANCE_BASES.iter().rposition(|&base| base as usize <= distance).unwrap();
+        self.write_code(code as u32, 5);
+        self.write((distance - DISTANCE_BASES[code] as usize) as u32, DISTANCE_EXTRA_BITS[code] as u32);
+    }
+
+    fn flush(&mut self) {
+        if self.count > 0 {
+            self.write(0, 8 - self.count);
+        }
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 })
+    })
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (a, b) = bytes.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        position: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, count: usize) -> usize {
+            let mut value = 0;
+            for i in 0..count {
+                let bit = self.bytes[self.position / 8] >> (self.position % 8) & 1;
+                value |= (bit as usize) << i;
+                self.position += 1;
+            }
+            value
+        }
+
+        fn code(&mut self, count: usize) -> usize {
+            (0..count).fold(0, |code, _| code << 1 | self.bits(1))
+        }
+    }
+
+    // Inflates a single fixed Huffman block, which is all `zlib` writes.
+    fn inflate(stream: &[u8]) -> Vec<u8> {
+        assert_eq!(stream[..2], [0x78, 0x01]);
+        let mut reader = BitReader { bytes: stream, position: 16 };
+        assert_eq!(reader.bits(3), 0b011, "expected a final fixed Huffman block");
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            let symbol = match reader.code(7) {
+                code @ 0..=0x17 => code + 256,
+                code => match code << 1 | reader.bits(1) {
+                    code @ 0x30..=0xBF => code - 0x30,
+                    code @ 0xC0..=0xC7 => code - 0xC0 + 280,
+                    code => (code << 1 | reader.bits(1)) - 0x190 + 144,
+                },
+            };
+            match symbol {
+                0..=255 => out.push(symbol as u8),
+                256 => break,
+                _ => {
+                    let code = symbol - 257;
+                    let length = LENGTH_BASES[code] as usize + reader.bits(LENGTH_EXTRA_BITS[code] as usize);
+                    let code = reader.code(5);
+                    let distance = DISTANCE_BASES[code] as usize + reader.bits(DISTANCE_EXTRA_BITS[code] as usize);
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance]);
+                    }
+                },
+            }
+        }
+        assert_eq!(stream[stream.len() - 4..], adler32(&out).to_be_bytes());
+        out
+    }
+
+    // (type, data) for every chunk, checking each CRC on the way.
+    fn chunks(png: &[u8]) -> Vec<(String, Vec<u8>)> {
+        assert_eq!(png[..8], SIGNATURE);
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let crc = u32::from_be_bytes(rest[8 + length..12 + length].try_into().unwrap());
+            assert_eq!(crc, crc32(&rest[4..8 + length]));
+            chunks.push((String::from_utf8(rest[4..8].to_vec()).unwrap(), rest[8..8 + length].to_vec()));
+            rest = &rest[12 + length..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(adler32(b""), 1);
+    }
+
+    #[test]
+    fn test_deflate_round_trips() {
+        let mut data: Vec<u8> = (0..=255).collect();
+        data.extend(std::iter::repeat_n(7, 1000));
+        data.extend((0..600).map(|i| (i % 5) as u8));
+        assert_eq!(inflate(&zlib(&data, 5)), data);
+        assert_eq!(inflate(&zlib(&[], 1)), []);
+    }
+
+    #[test]
+    fn test_screenshot() {
+        // The top of a 0 glyph, then a pixel lit on both planes.
+        let mut display = Display::default();
+        display.draw_row(0, 0, 0xF0, 8, 0b01, false);
+        display.draw_row(0, 1, 0x90, 8, 0b01, false);
+        display.draw_row(4, 0, 0x80, 8, 0b11, false);
+
+        let palette = Palette::default();
+        let png = encode_display(&display, &palette, 3);
+        let chunks = chunks(&png);
+        let kinds: Vec<&str> = chunks.iter().map(|(kind, _)| kind.as_str()).collect();
+        assert_eq!(kinds, ["IHDR", "PLTE", "IDAT", "IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 192, 0, 0, 0, 96, 8, 3, 0, 0, 0]);
+        assert_eq!(chunks[1].1, [0x00, 0x00, 0x00, 0x00, 0xFF, 0x00, 0xFF, 0x55, 0x00, 0xFF, 0xFF, 0x55]);
+
+        let scanlines = inflate(&chunks[2].1);
+        assert_eq!(scanlines.len(), 96 * (1 + 192));
+        let pixel = |x: usize, y: usize| scanlines[y * 193 + 1 + x];
+        assert_eq!((0..14).map(|x| pixel(x, 0)).collect::<Vec<u8>>(), [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 3, 3]);
+        assert_eq!((0..14).map(|x| pixel(x, 5)).collect::<Vec<u8>>(), [1, 1, 1, 0, 0, 0, 0, 0, 0, 1, 1, 1, 0, 0]);
+        assert!(scanlines.chunks(193).all(|row| row[0] == 0));
+        assert!(png.len() < 300, "{} bytes", png.len());
+    }
+}
@@ -0,0 +1,197 @@
+use crate::savestate::{StateError, StateReader, StateWriter};
+use std::fmt;
+use std::str::FromStr;
+
+// The original CHIP-8 interpreter and its descendants disagree on a
+// handful of instructions. Every such behaviour is selected here instead
+// of being hard-coded in `CHIP8::execute`.
+
+// When Fx0A hands over its key: the COSMAC VIP waits for the key to be
+// released, some later interpreters return as soon as it goes down.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyWaitMode {
+    #[default]
+    OnRelease,
+    OnPress,
+}
+
+// Where Fx55/Fx65 leave the index register once they are done.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexIncrement {
+    Unchanged,
+    ByX,
+    ByXPlusOne,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    // 8xy6/8xyE shift VY into VX instead of shifting VX in place.
+    pub shift_uses_vy: bool,
+    pub index_increment: IndexIncrement,
+    // Bnnn jumps to xnn + VX instead of nnn + V0.
+    pub jump_uses_vx: bool,
+    // 8xy1/8xy2/8xy3 clear VF.
+    pub logic_resets_vf: bool,
+    // Sprites are cut off at the screen edges instead of wrapping around.
+    // The starting coordinate always wraps.
+    pub clip_sprites: bool,
+    pub key_wait: KeyWaitMode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Platform {
+    CosmacVip,
+    Chip48,
+    SuperChip,
+    XoChip,
+}
+
+impl Platform {
+    pub fn memory_size(self) -> usize {
+        match self {
+            Platform::XoChip => crate::chip8::XO_CHIP_MEMORY_SIZE,
+            _ => crate::chip8::MEMORY_SIZE,
+        }
+    }
+
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::CosmacVip => Quirks::cosmac_vip(),
+            Platform::Chip48 => Quirks::chip48(),
+            Platform::SuperChip => Quirks::superchip(),
+            Platform::XoChip => Quirks::xochip(),
+        }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Platform, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "vip" | "cosmac-vip" | "chip8" | "chip-8" => Ok(Platform::CosmacVip),
+            "chip48" | "chip-48" => Ok(Platform::Chip48),
+            "schip" | "superchip" | "super-chip" => Ok(Platform::SuperChip),
+            "xochip" | "xo-chip" => Ok(Platform::XoChip),
+            _ => Err(format!("unknown platform '{}', expected vip, chip48, schip or xochip", s)),
+        }
+    }
+}
+
+// The short names `from_str` accepts.
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Platform::CosmacVip => "vip",
+            Platform::Chip48 => "chip48",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xochip",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Unconfigured machines behave like the COSMAC VIP.
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::cosmac_vip()
+    }
+}
+
+impl Quirks {
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            index_increment: IndexIncrement::ByXPlusOne,
+            jump_uses_vx: false,
+            logic_resets_vf: true,
+            clip_sprites: true,
+            key_wait: KeyWaitMode::OnRelease,
+        }
+    }
+
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            index_increment: IndexIncrement::ByX,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+            key_wait: KeyWaitMode::OnPress,
+        }
+    }
+
+    pub fn superchip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            index_increment: IndexIncrement::Unchanged,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+            key_wait: KeyWaitMode::OnRelease,
+        }
+    }
+
+    pub fn xochip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            index_increment: IndexIncrement::ByXPlusOne,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false,
+            key_wait: KeyWaitMode::OnRelease,
+        }
+    }
+}
+
+impl Quirks {
+    pub(crate) fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.shift_uses_vy);
+        writer.u8(match self.index_increment {
+            IndexIncrement::Unchanged => 0,
+            IndexIncrement::ByX => 1,
+            IndexIncrement::ByXPlusOne => 2,
+        });
+        writer.bool(self.jump_uses_vx);
+        writer.bool(self.logic_resets_vf);
+        writer.bool(self.clip_sprites);
+        writer.u8(match self.key_wait {
+            KeyWaitMode::OnRelease => 0,
+            KeyWaitMode::OnPress => 1,
+        });
+    }
+
+    pub(crate) fn load_state(reader: &mut StateReader) -> Result<Quirks, StateError> {
+        Ok(Quirks {
+            shift_uses_vy: reader.bool()?,
+            index_increment: match reader.u8()? {
+                0 => IndexIncrement::Unchanged,
+                1 => IndexIncrement::ByX,
+                2 => IndexIncrement::ByXPlusOne,
+                _ => return Err(StateError::Invalid("index increment quirk")),
+            },
+            jump_uses_vx: reader.bool()?,
+            logic_resets_vf: reader.bool()?,
+            clip_sprites: reader.bool()?,
+            key_wait: match reader.u8()? {
+                0 => KeyWaitMode::OnRelease,
+                1 => KeyWaitMode::OnPress,
+                _ => return Err(StateError::Invalid("key wait quirk")),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_platform() {
+        assert_eq!("vip".parse(), Ok(Platform::CosmacVip));
+        assert_eq!("CHIP-48".parse(), Ok(Platform::Chip48));
+        assert_eq!("schip".parse(), Ok(Platform::SuperChip));
+        assert_eq!("xo-chip".parse(), Ok(Platform::XoChip));
+        assert!("megachip".parse::<Platform>().is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::VecDeque;
+
+// History of save states for stepping backwards one frame at a time.
+//
+// Only the newest state is kept whole. Every older one is stored as the
+// XOR against its successor, run-length encoded, so a frame where a
+// sprite moved costs a few dozen bytes instead of the full 12 KiB (or
+// 70 KiB for XO-CHIP). When the deltas outgrow the budget the oldest are
+// dropped.
+//
+// Push the state after every frame, so the newest one is what's on screen
+// and `pop` steps back to the frame before it.
+#[derive(Debug)]
+pub struct Rewind {
+    budget: usize,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    used: usize,
+}
+
+impl Rewind {
+    // `budget` is in bytes and covers the newest state plus every delta.
+    pub fn new(budget: usize) -> Rewind {
+        Rewind {
+            budget,
+            latest: None,
+            deltas: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    // Number of frames that can be stepped back.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.used = 0;
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if state.len() > self.budget {
+            self.clear();
+            return;
+        }
+        match self.latest.take() {
+            // A platform change alters the state size; older frames can't
+            // be diffed against it, so history starts over.
+            Some(previous) if previous.len() == state.len() => {
+                let delta = encode_delta(&previous, &state);
+                self.used += delta.len();
+                self.deltas.push_back(delta);
+                self.used = self.used - previous.len() + state.len();
+            }
+            Some(_) => {
+                self.clear();
+                self.used = state.len();
+            }
+            None => self.used = state.len(),
+        }
+        self.latest = Some(state);
+
+        while self.used > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.used -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    // Drops the newest state and returns the one before it, which becomes
+    // the newest. Returns `None` once the history is used up.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let delta = self.deltas.pop_back()?;
+        let latest = self.latest.as_mut()?;
+        self.used -= delta.len();
+        apply_delta(latest, &delta);
+        Some(latest.clone())
+    }
+}
+
+// Delta encoding: pairs of LEB128 lengths, a run of bytes equal in both
+// states followed by a run of `count` XORed bytes stored literally.
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut position = 0;
+    while position < old.len() {
+        let same = old[position..].iter().zip(&new[position..]).take_while(|(a, b)| a == b).count();
+        position += same;
+        let changed = old[position..].iter().zip(&new[position..]).take_while(|(a, b)| a != b).count();
+        write_length(&mut delta, same);
+        write_length(&mut delta, changed);
+        delta.extend(old[position..position + changed].iter().zip(&new[position..]).map(|(a, b)| a ^ b));
+        position += changed;
+    }
+    delta
+}
+
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let mut input = delta.iter().copied();
+    let mut position = 0;
+    while let Some(same) = read_length(&mut input) {
+        position += same;
+        let changed = read_length(&mut input).unwrap_or(0);
+        for (byte, xor) in state[position..position + changed].iter_mut().zip(&mut input) {
+            *byte ^= xor;
+        }
+        position += changed;
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, mut length: usize) {
+    while length >= 0x80 {
+        out.push(length as u8 | 0x80);
+        length >>= 7;
+    }
+    out.push(length as u8);
+}
+
+fn read_length(input: &mut impl Iterator<Item = u8>) -> Option<usize> {
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        let byte = input.next()?;
+        length |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(length);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state(frame: u8) -> Vec<u8> {
+        let mut state = vec![0u8; 4096];
+        state[frame as usize * 7] = frame;
+        state[4000] = frame.wrapping_mul(3);
+        state
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let old = state(1);
+        let mut new = state(2);
+        new[300..500].iter_mut().for_each(|byte| *byte = 0xAA);
+        let delta = encode_delta(&old, &new);
+        assert!(delta.len() < 220);
+        apply_delta(&mut new, &delta);
+        assert_eq!(new, old);
+    }
+
+    #[test]
+    fn test_steps_back_in_order() {
+        let mut rewind = Rewind::new(1 << 20);
+        assert_eq!(rewind.pop(), None);
+        for frame in 0..100 {
+            rewind.push(state(frame));
+        }
+        assert_eq!(rewind.len(), 99);
+        for frame in (0..99).rev() {
+            assert_eq!(rewind.pop(), Some(state(frame)));
+        }
+        assert_eq!(rewind.pop(), None);
+
+        // Playing on from a rewound frame builds history from there.
+        rewind.push(state(50));
+        assert_eq!(rewind.pop(), Some(state(0)));
+    }
+
+    #[test]
+    fn test_first_pop_steps_back_one_frame() {
+        // V0 counts frames: ADD V0, 1; JP 0x200.
+        let mut chip = crate::CHIP8::default();
+        chip.load_from_slice(&[0x70, 0x01, 0x12, 0x00], None).unwrap();
+        let mut rewind = Rewind::new(1 << 20);
+        rewind.push(chip.save_state());
+        for _ in 0..5 {
+            chip.run_frame(2).unwrap();
+            rewind.push(chip.save_state());
+        }
+        assert_eq!(chip.registers()[0], 5);
+
+        for frame in (0..5).rev() {
+            chip.load_state(&rewind.pop().unwrap()).unwrap();
+            assert_eq!(chip.registers()[0], frame);
+        }
+        assert_eq!(rewind.pop(), None);
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut rewind = Rewind::new(4096 + 100);
+        for frame in 0..100 {
+            rewind.push(state(frame));
+            assert!(rewind.memory_used() <= 4096 + 100);
+        }
+        let kept = rewind.len();
+        assert!(kept > 5 && kept < 99, "{} frames kept", kept);
+        for frame in (99 - kept..99).rev() {
+            assert_eq!(rewind.pop(), Some(state(frame as u8)));
+        }
+        assert_eq!(rewind.pop(), None);
+    }
+
+    #[test]
+    fn test_size_change_resets_history() {
+        let mut rewind = Rewind::new(1 << 20);
+        rewind.push(state(1));
+        rewind.push(state(2));
+        rewind.push(vec![0u8; 100]);
+        assert!(rewind.is_empty());
+        assert_eq!(rewind.memory_used(), 100);
+    }
+}
@@ -0,0 +1,68 @@
+// Random numbers for CXNN. SplitMix64: its entire state is one u64, so a
+// save state can hold it and a seed reproduces a run bit for bit, on any
+// machine and with any version of the rand crate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    // Seeded by the OS, for runs that don't need to be repeatable.
+    pub fn from_entropy() -> Rng {
+        Rng::new(rand::random())
+    }
+
+    // Everything needed to continue the sequence: `Rng::new(rng.state())`
+    // picks up exactly where `rng` is.
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Any of the 256 values, equally likely.
+    pub fn byte(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_known_sequence() {
+        // Reference values for SplitMix64 seeded with 0.
+        let mut rng = Rng::new(0);
+        assert_eq!(rng.next_u64(), 0xE220_A839_7B1D_CDAF);
+        assert_eq!(rng.next_u64(), 0x6E78_9E6A_A1B9_65F4);
+    }
+
+    #[test]
+    fn test_state_resumes() {
+        let mut rng = Rng::new(42);
+        rng.byte();
+        let mut resumed = Rng::new(rng.state());
+        assert_eq!((0..100).map(|_| rng.byte()).collect::<Vec<_>>(), (0..100).map(|_| resumed.byte()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_every_byte() {
+        let mut rng = Rng::new(7);
+        let mut seen = [false; 256];
+        for _ in 0..10_000 {
+            seen[rng.byte() as usize] = true;
+        }
+        assert!(seen.iter().all(|&seen| seen));
+    }
+}
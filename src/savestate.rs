@@ -0,0 +1,196 @@
+use std::fmt;
+
+// Save state file format. Every integer is little endian.
+//
+//   magic            4 bytes  "CH8S"
+//   version          u16      SAVE_STATE_VERSION when written
+//   memory length    u32
+//   memory           memory length bytes
+//   display          u8 hires, u8 selected planes, 128 * 64 pixel bytes
+//   registers        16 bytes, V0 to VF
+//   flags            16 bytes, SUPER-CHIP RPL flags
+//   sp               u8, followed by sp u16 stack entries, oldest first
+//   pc, index        u16 each
+//   delay, sound     u8 each
+//   keypad           u16, bit n set while key n is held
+//   key wait         u8 state (0 idle, 1 waiting, 2 pressed, 3 done), u8 key
+//   quirks           u8 shift_uses_vy, u8 index_increment (0 unchanged,
+//                    1 by x, 2 by x + 1), u8 jump_uses_vx, u8 logic_resets_vf,
+//                    u8 clip_sprites, u8 key_wait (0 on release, 1 on press)
+//   halted           u8
+//   audio pattern    16 bytes
+//   pitch            u8
+//   rng              u64, the CXNN generator's state
+//
+// New fields only ever get appended, behind a version bump, so readers can
+// keep accepting every older version.
+pub const SAVE_STATE_MAGIC: &[u8; 4] = b"CH8S";
+pub const SAVE_STATE_VERSION: u16 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => write!(f, "unsupported save state version {}", version),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Invalid(what) => write!(f, "save state has an invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        let mut writer = StateWriter::default();
+        writer.bytes(SAVE_STATE_MAGIC);
+        writer.u16(SAVE_STATE_VERSION);
+        writer
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    version: u16,
+}
+
+impl<'a> StateReader<'a> {
+    // Checks the header and leaves the reader right after it.
+    pub fn new(data: &'a [u8]) -> Result<StateReader<'a>, StateError> {
+        let mut reader = StateReader { data, version: 0 };
+        if reader.bytes(SAVE_STATE_MAGIC.len()).map_err(|_| StateError::BadMagic)? != SAVE_STATE_MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        reader.version = reader.u16()?;
+        if reader.version == 0 || reader.version > SAVE_STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(reader.version));
+        }
+        Ok(reader)
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid("boolean")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    // Trailing bytes mean the file is from somewhere else, not a newer us.
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.data.is_empty() { Ok(()) } else { Err(StateError::Invalid("length")) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let data = StateWriter::new().finish();
+        assert_eq!(&data[..4], b"CH8S");
+        assert_eq!(StateReader::new(&data).unwrap().version(), SAVE_STATE_VERSION);
+
+        assert_eq!(StateReader::new(b"CH8").unwrap_err(), StateError::BadMagic);
+        assert_eq!(StateReader::new(b"RIFF\x01\x00").unwrap_err(), StateError::BadMagic);
+        assert_eq!(StateReader::new(b"CH8S\xFF\x00").unwrap_err(), StateError::UnsupportedVersion(0xFF));
+        assert_eq!(StateReader::new(b"CH8S\x01").unwrap_err(), StateError::Truncated);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.u8(0xAB);
+        writer.bool(true);
+        writer.u16(0x1234);
+        writer.u32(0xDEADBEEF);
+        writer.u64(0x0123_4567_89AB_CDEF);
+        let data = writer.finish();
+        assert_eq!(&data[6..14], &[0xAB, 0x01, 0x34, 0x12, 0xEF, 0xBE, 0xAD, 0xDE]);
+
+        let mut reader = StateReader::new(&data).unwrap();
+        assert_eq!(reader.u8(), Ok(0xAB));
+        assert_eq!(reader.bool(), Ok(true));
+        assert_eq!(reader.u16(), Ok(0x1234));
+        assert_eq!(reader.u32(), Ok(0xDEADBEEF));
+        assert_eq!(reader.u64(), Ok(0x0123_4567_89AB_CDEF));
+        assert_eq!(reader.u8(), Err(StateError::Truncated));
+        assert!(reader.finish().is_ok());
+    }
+}
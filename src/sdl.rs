@@ -0,0 +1,470 @@
+use crate::{close_tracer, Args, FileTracer, GifSession, MovieSession};
+use chip8::{AudioSink, Display, EmulationError, NullSink, Palette, Rewind, Rgb, SquareWave, CHIP8};
+use chip8::debugger::{self, Debugger, Stop};
+use chip8::gdb::GdbStub;
+use chip8::movie::apply_keys;
+use chip8::png;
+use chip8::timer::TimerClock;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::event::Event;
+use sdl2::keyboard::Scancode;
+use sdl2::pixels::Color;
+use sdl2::rect::Point;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Instant;
+
+// Background shown instead of the palette's once the machine has faulted.
+const FAULT_BACKGROUND: Color = Color::RGB(0x60, 0x00, 0x00);
+
+pub enum Kbd {
+    KeyDown(Scancode),
+    KeyUp(Scancode),
+    Quit,
+}
+
+// Drains every pending event so no press or release is lost between frames.
+fn poll_keys(event_pump: &mut sdl2::EventPump) -> Vec<Kbd> {
+    event_pump
+        .poll_iter()
+        .filter_map(|event| match event {
+            Event::Quit { .. } => Some(Kbd::Quit),
+            Event::KeyDown {
+                scancode: Some(scancode),
+                repeat: false,
+                ..
+            } => Some(Kbd::KeyDown(scancode)),
+            Event::KeyUp {
+                scancode: Some(scancode),
+                ..
+            } => Some(Kbd::KeyUp(scancode)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn to_color(rgb: Rgb) -> Color {
+    Color::RGB(rgb.r, rgb.g, rgb.b)
+}
+
+fn scancode_to_keypad(scancode: Scancode) -> Option<u8> {
+    match scancode {
+        Scancode::Num1 => Some(0x1),
+        Scancode::Num2 => Some(0x2),
+        Scancode::Num3 => Some(0x3),
+        Scancode::Num4 => Some(0xC),
+        Scancode::Q => Some(0x4),
+        Scancode::W => Some(0x5),
+        Scancode::E => Some(0x6),
+        Scancode::R => Some(0xD),
+        Scancode::A => Some(0x7),
+        Scancode::S => Some(0x8),
+        Scancode::D => Some(0x9),
+        Scancode::F => Some(0xE),
+        Scancode::Z => Some(0xA),
+        Scancode::X => Some(0x0),
+        Scancode::C => Some(0xB),
+        Scancode::V => Some(0xF),
+        _ => None,
+    }
+}
+
+struct Beeper(SquareWave);
+
+impl AudioCallback for Beeper {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        self.0.fill(out);
+    }
+}
+
+// The device plays all the time; the wave itself fades in and out.
+struct SdlAudio(AudioDevice<Beeper>);
+
+impl AudioSink for SdlAudio {
+    fn set_tone(&mut self, active: bool) {
+        self.0.lock().0.set_active(active);
+    }
+}
+
+fn open_audio(sdl_context: &sdl2::Sdl, args: &Args) -> Result<SdlAudio, String> {
+    let desired = AudioSpecDesired {
+        freq: Some(44_100),
+        channels: Some(1),
+        samples: Some(512),
+    };
+    let device = sdl_context
+        .audio()?
+        .open_playback(None, &desired, |spec| Beeper(SquareWave::new(spec.freq as u32, args.beep, args.volume)))?;
+    device.resume();
+    Ok(SdlAudio(device))
+}
+
+// F1-F4 pick a save state slot, F5 saves into it and F9 loads it back.
+// Backspace rewinds for as long as it is held, F11 starts and stops
+// recording a GIF and F12 takes a screenshot.
+enum Hotkey {
+    SelectSlot(u8),
+    SaveState,
+    LoadState,
+    Rewind,
+    RecordGif,
+    Screenshot,
+}
+
+fn scancode_to_hotkey(scancode: Scancode) -> Option<Hotkey> {
+    match scancode {
+        Scancode::F1 => Some(Hotkey::SelectSlot(1)),
+        Scancode::F2 => Some(Hotkey::SelectSlot(2)),
+        Scancode::F3 => Some(Hotkey::SelectSlot(3)),
+        Scancode::F4 => Some(Hotkey::SelectSlot(4)),
+        Scancode::F5 => Some(Hotkey::SaveState),
+        Scancode::F9 => Some(Hotkey::LoadState),
+        Scancode::Backspace => Some(Hotkey::Rewind),
+        Scancode::F11 => Some(Hotkey::RecordGif),
+        Scancode::F12 => Some(Hotkey::Screenshot),
+        _ => None,
+    }
+}
+
+// Slots live next to the ROM: game.ch8 saves to game.state1 and so on.
+fn state_path(args: &Args, slot: u8) -> PathBuf {
+    args.rom().with_extension(format!("state{}", slot))
+}
+
+// Screenshots and GIFs live next to the ROM too, as game-1.png,
+// game-2.png and so on, never overwriting an earlier one.
+fn numbered_path(args: &Args, extension: &str) -> PathBuf {
+    let rom = args.rom();
+    let stem = rom.file_stem().unwrap_or_default().to_string_lossy();
+    (1..)
+        .map(|number| rom.with_file_name(format!("{}-{}.{}", stem, number, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+// Written at the size of the window, so high resolution pixels are half
+// as big as low resolution ones, just like on screen.
+fn save_screenshot(chip: &CHIP8, args: &Args, palette: &Palette) {
+    let path = numbered_path(args, "png");
+    let scale = (args.scale as usize * chip8::chip8::DISPLAY_WIDTH / chip.display().width()).max(1);
+    match png::save_display(&path, chip.display(), palette, scale) {
+        Ok(()) => eprintln!("Saved screenshot to {}", path.display()),
+        Err(error) => eprintln!("Could not save {}: {}", path.display(), error),
+    }
+}
+
+// Snapshots carry the keypad as it was back then. Let go of every key
+// that isn't actually held down any more.
+fn release_unheld_keys(chip: &mut CHIP8, events: &sdl2::EventPump) {
+    let held: Vec<u8> = events
+        .keyboard_state()
+        .pressed_scancodes()
+        .filter_map(scancode_to_keypad)
+        .collect();
+    for key in (0..16).filter(|key| !held.contains(key)) {
+        if chip.is_key_pressed(key) {
+            chip.key_up(key);
+        }
+    }
+}
+
+const PROMPT: &str = "(chip8) ";
+
+// Reads debugger commands on a separate thread so the window keeps
+// rendering while the prompt waits for input.
+fn spawn_prompt() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+fn prompt() {
+    print!("{}", PROMPT);
+    io::stdout().flush().unwrap();
+}
+
+// One frame, either straight through, traced, or under the debugger's
+// control.
+fn run_frame(
+    chip: &mut CHIP8,
+    debugger: Option<&mut Debugger>,
+    tracer: Option<&mut FileTracer>,
+    instructions: usize,
+) -> Result<(), EmulationError> {
+    let Some(debugger) = debugger else {
+        return match tracer {
+            Some(tracer) => tracer.run_frame(chip, instructions),
+            None => chip.run_frame(instructions),
+        };
+    };
+    match debugger.run_frame(chip, instructions)? {
+        Some(Stop::Breakpoint(address)) => println!("\nbreakpoint at {:#05X}\n{}", address, debugger::registers(chip)),
+        Some(Stop::Stepped) => println!("\n{}", debugger::registers(chip)),
+        Some(Stop::Halted) => println!("\nprogram exited"),
+        None => return Ok(()),
+    }
+    prompt();
+    Ok(())
+}
+
+// Pixels showing `color`, the combination of planes lit at that spot, into
+// `points`, which is reused from frame to frame.
+fn pixels_to_draw(display: &Display, color: u8, points: &mut Vec<Point>) {
+    points.clear();
+    for y in 0..display.height() {
+        let mut mask = display.color_mask(y, color);
+        while mask != 0 {
+            let x = mask.leading_zeros();
+            points.push(Point::new(x as i32, y as i32));
+            mask &= !(1 << (127 - x));
+        }
+    }
+}
+
+pub fn run(
+    mut chip: CHIP8,
+    args: &Args,
+    mut gdb: Option<GdbStub>,
+    mut tracer: Option<FileTracer>,
+    mut movie: Option<MovieSession>,
+    mut gif: Option<GifSession>,
+) {
+    let palette: Palette = args.palette();
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+
+    let window = video_subsystem
+        .window(
+            "CHIP-8",
+            chip8::chip8::DISPLAY_WIDTH as u32 * args.scale,
+            chip8::chip8::DISPLAY_HEIGHT as u32 * args.scale,
+        )
+        .position_centered()
+        .build()
+        .unwrap();
+
+    let mut canvas = window.into_canvas().build().unwrap();
+    canvas.set_logical_size(64, 32).unwrap();
+
+    // A missing sound card shouldn't stop the game from running.
+    let mut audio: Box<dyn AudioSink> = match open_audio(&sdl_context, args) {
+        Ok(device) => Box::new(device),
+        Err(error) => {
+            eprintln!("No audio: {}", error);
+            Box::new(NullSink)
+        }
+    };
+
+    let mut events = sdl_context.event_pump().unwrap();
+    let mut points = Vec::new();
+    let mut clock = TimerClock::default();
+    let mut last_frame = Instant::now();
+    let mut fault: Option<EmulationError> = None;
+    let mut slot = 1;
+    // The newest state in the history is always the one on screen, so the
+    // first step back lands on the frame before it.
+    let mut rewind = Rewind::new(args.rewind << 20);
+    if args.rewind > 0 {
+        rewind.push(chip.save_state());
+    }
+    let mut rewinding = false;
+    // With a movie the keypad only changes between frames, from `held`,
+    // so recording and playback see exactly the same input.
+    let mut held: u16 = 0;
+    let instructions = movie.as_ref().map_or(args.instructions_per_frame, MovieSession::instructions_per_frame);
+    let mut debugger = args.debug.then(|| Debugger::new(true));
+    let commands = args.debug.then(spawn_prompt);
+    if args.debug {
+        println!("Paused at {:#05X}. Type help for the debugger commands.", chip.pc());
+        prompt();
+    }
+
+    'main: loop {
+        for key in poll_keys(&mut events) {
+            match key {
+                Kbd::Quit => break 'main,
+                Kbd::KeyDown(scancode) => {
+                    match scancode_to_keypad(scancode) {
+                        Some(key) if movie.is_some() => held |= 1 << key,
+                        Some(key) => chip.key_down(key),
+                        None => {}
+                    }
+                    match scancode_to_hotkey(scancode) {
+                        Some(Hotkey::SelectSlot(new_slot)) => {
+                            slot = new_slot;
+                            canvas.window_mut().set_title(&format!("CHIP-8 - slot {}", slot)).unwrap();
+                        }
+                        Some(Hotkey::SaveState) => {
+                            let path = state_path(args, slot);
+                            match chip.save_state_to_file(&path) {
+                                Ok(()) => eprintln!("Saved state to {}", path.display()),
+                                Err(error) => eprintln!("Could not save {}: {}", path.display(), error),
+                            }
+                        }
+                        Some(Hotkey::LoadState | Hotkey::Rewind) if movie.is_some() => {
+                            eprintln!("Loading states and rewinding would break the movie");
+                        }
+                        Some(Hotkey::LoadState) => {
+                            let path = state_path(args, slot);
+                            match chip.load_state_from_file(&path) {
+                                Ok(()) => {
+                                    eprintln!("Loaded state from {}", path.display());
+                                    fault = None;
+                                    if args.rewind > 0 {
+                                        rewind.push(chip.save_state());
+                                    }
+                                    canvas.window_mut().set_title(&format!("CHIP-8 - slot {}", slot)).unwrap();
+                                }
+                                Err(error) => eprintln!("Could not load {}: {}", path.display(), error),
+                            }
+                        }
+                        Some(Hotkey::Rewind) => rewinding = true,
+                        Some(Hotkey::RecordGif) => match gif.take() {
+                            Some(recording) => recording.finish(),
+                            None => {
+                                let path = numbered_path(args, "gif");
+                                match GifSession::create(path.clone(), args) {
+                                    Ok(recording) => {
+                                        eprintln!("Recording to {}", path.display());
+                                        gif = Some(recording);
+                                    }
+                                    Err(error) => eprintln!("Could not create {}: {}", path.display(), error),
+                                }
+                            }
+                        },
+                        Some(Hotkey::Screenshot) => save_screenshot(&chip, args, &palette),
+                        None => {}
+                    }
+                }
+                Kbd::KeyUp(scancode) => {
+                    match scancode_to_keypad(scancode) {
+                        Some(key) if movie.is_some() => held &= !(1 << key),
+                        Some(key) => chip.key_up(key),
+                        None => {}
+                    }
+                    if let Some(Hotkey::Rewind) = scancode_to_hotkey(scancode) {
+                        rewinding = false;
+                    }
+                }
+            }
+        }
+
+        if let (Some(debugger), Some(commands)) = (debugger.as_mut(), commands.as_ref()) {
+            for line in commands.try_iter() {
+                if !line.trim().is_empty() {
+                    match line.parse() {
+                        Ok(command) => println!("{}", debugger.command(command, &chip)),
+                        Err(error) => println!("{}", error),
+                    }
+                }
+                prompt();
+            }
+        }
+
+        if let Some(stub) = gdb.as_mut() {
+            stub.poll(&mut chip);
+            if stub.is_killed() {
+                break 'main;
+            }
+        }
+
+        let now = Instant::now();
+        let frames = clock.advance(now - last_frame);
+        last_frame = now;
+        if frames == 0 {
+            std::thread::sleep(clock.until_next_tick());
+            continue;
+        }
+
+        // Ticks already in the GIF; the ones the machine didn't run, while
+        // paused or after a fault, show the screen as it is.
+        let mut recorded = 0;
+        if rewinding {
+            for _ in 0..frames {
+                if let Some(state) = rewind.pop() {
+                    chip.load_state(&state).expect("rewind history holds valid states");
+                    release_unheld_keys(&mut chip, &events);
+                    fault = None;
+                }
+                if let Some(gif) = gif.as_mut() {
+                    gif.frame(chip.display());
+                    recorded += 1;
+                }
+            }
+        } else if fault.is_none() {
+            for _ in 0..frames {
+                if debugger.as_ref().is_some_and(Debugger::is_paused) || gdb.as_ref().is_some_and(GdbStub::is_paused) {
+                    break;
+                }
+                let keys = movie.as_ref().map(|movie| movie.keys(held));
+                if let Some(keys) = keys {
+                    apply_keys(&mut chip, keys);
+                }
+                let result = match gdb.as_mut() {
+                    Some(stub) => stub.run_frame(&mut chip, instructions),
+                    None => run_frame(&mut chip, debugger.as_mut(), tracer.as_mut(), instructions),
+                };
+                if let (Some(movie), Some(keys), Ok(())) = (movie.as_mut(), keys, &result) {
+                    movie.after_frame(keys, &chip);
+                }
+                if args.rewind > 0 {
+                    rewind.push(chip.save_state());
+                }
+                if let (Some(gif), Ok(())) = (gif.as_mut(), &result) {
+                    gif.frame(chip.display());
+                    recorded += 1;
+                }
+                if let Err(error) = result {
+                    // Keep the window up with the last frame so the fault can be inspected.
+                    eprintln!("CHIP-8 fault: {}", error);
+                    canvas
+                        .window_mut()
+                        .set_title(&format!("CHIP-8 - fault: {}", error))
+                        .unwrap();
+                    fault = Some(error);
+                    break;
+                }
+            }
+        }
+        if let Some(gif) = gif.as_mut() {
+            for _ in recorded..frames {
+                gif.frame(chip.display());
+            }
+        }
+        audio.set_tone(!rewinding && fault.is_none() && chip.is_sound_playing());
+
+        let resolution = (chip.display().width() as u32, chip.display().height() as u32);
+        if canvas.logical_size() != resolution {
+            canvas.set_logical_size(resolution.0, resolution.1).unwrap();
+        }
+
+        canvas.set_draw_color(if fault.is_some() { FAULT_BACKGROUND } else { to_color(palette.color(0)) });
+        canvas.clear();
+
+        for (color, rgb) in palette.0.iter().enumerate().skip(1) {
+            pixels_to_draw(chip.display(), color as u8, &mut points);
+            canvas.set_draw_color(to_color(*rgb));
+            canvas.draw_points(points.as_slice()).unwrap();
+        }
+
+        canvas.present();
+    }
+
+    close_tracer(tracer);
+    if let Some(movie) = movie {
+        movie.finish();
+    }
+    if let Some(gif) = gif {
+        gif.finish();
+    }
+}
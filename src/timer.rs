@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+pub const TIMER_FREQUENCY: u32 = 60; // Delay and sound timers count down at 60 Hz.
+
+// Turns elapsed host time into a number of 60 Hz timer ticks, carrying
+// the remainder over so no time is lost between calls. The emulator
+// itself never looks at a clock, so headless runs can just call
+// `CHIP8::tick_timers` (or `run_frame`) directly.
+#[derive(Debug, Clone, Copy)]
+pub struct TimerClock {
+    period: Duration,
+    accumulated: Duration,
+}
+
+impl Default for TimerClock {
+    fn default() -> TimerClock {
+        TimerClock::new(TIMER_FREQUENCY)
+    }
+}
+
+impl TimerClock {
+    pub fn new(frequency: u32) -> TimerClock {
+        TimerClock {
+            period: Duration::from_secs(1) / frequency,
+            accumulated: Duration::ZERO,
+        }
+    }
+
+    // Returns how many ticks are due after `elapsed` more time has passed.
+    pub fn advance(&mut self, elapsed: Duration) -> u32 {
+        self.accumulated += elapsed;
+        let ticks = (self.accumulated.as_nanos() / self.period.as_nanos()) as u32;
+        self.accumulated -= self.period * ticks;
+        ticks
+    }
+
+    pub fn until_next_tick(&self) -> Duration {
+        self.period.saturating_sub(self.accumulated)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_clock_ticks_at_60hz() {
+        let mut clock = TimerClock::default();
+        assert_eq!(clock.advance(Duration::from_secs(1)), 60);
+        assert_eq!(clock.advance(Duration::from_millis(500)), 30);
+    }
+
+    #[test]
+    fn test_clock_carries_remainder() {
+        let mut clock = TimerClock::default();
+        let mut ticks = 0;
+        // 1000 steps of 1ms must still add up to exactly 60 ticks.
+        for _ in 0..1000 {
+            ticks += clock.advance(Duration::from_millis(1));
+        }
+        assert_eq!(ticks, 60);
+    }
+
+    #[test]
+    fn test_clock_until_next_tick() {
+        let mut clock = TimerClock::new(10);
+        assert_eq!(clock.advance(Duration::from_millis(30)), 0);
+        assert_eq!(clock.until_next_tick(), Duration::from_millis(70));
+    }
+}
@@ -0,0 +1,198 @@
+use crate::chip8::{Instruction, CHIP8};
+use crate::error::EmulationError;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+// Execution trace, one line per instruction, showing the machine as it
+// was right before the instruction ran:
+//
+//   0000000042 0206 8016 SHR V0, V1         V 01 02 00 .. 00 I 0000 SP 00 DT 00 ST 00
+//
+// Fields are the cycle (instructions executed before this one, counting
+// from 0), pc, raw opcode, mnemonic padded to 18 columns, V0-VF, index,
+// stack depth and the timers. Every field has a fixed width, so traces of
+// two runs line up and `diff` points at the first instruction where they
+// part ways.
+const MNEMONIC_WIDTH: usize = 18;
+
+#[derive(Debug)]
+pub struct Tracer<W: Write> {
+    out: W,
+    cycle: u64,
+    addresses: RangeInclusive<u16>,
+    cycles: RangeInclusive<u64>,
+    // The first write error; tracing stops there and `finish` reports it.
+    error: Option<io::Error>,
+}
+
+impl Tracer<BufWriter<File>> {
+    pub fn create(path: &Path) -> io::Result<Tracer<BufWriter<File>>> {
+        Ok(Tracer::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W) -> Tracer<W> {
+        Tracer {
+            out,
+            cycle: 0,
+            addresses: 0..=u16::MAX,
+            cycles: 0..=u64::MAX,
+            error: None,
+        }
+    }
+
+    // Only log instructions whose address falls in `addresses`.
+    pub fn with_addresses(self, addresses: RangeInclusive<u16>) -> Tracer<W> {
+        Tracer { addresses, ..self }
+    }
+
+    // Only log instructions whose cycle falls in `cycles`.
+    pub fn with_cycles(self, cycles: RangeInclusive<u64>) -> Tracer<W> {
+        Tracer { cycles, ..self }
+    }
+
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    // `CHIP8::step`, logging the instruction first.
+    pub fn step(&mut self, chip: &mut CHIP8) -> Result<(), EmulationError> {
+        if chip.is_halted() {
+            return Ok(());
+        }
+        if self.error.is_none() && self.cycles.contains(&self.cycle) && self.addresses.contains(&chip.pc()) {
+            if let Err(error) = writeln!(self.out, "{}", trace_line(self.cycle, chip)) {
+                self.error = Some(error);
+            }
+        }
+        self.cycle += 1;
+        chip.step()
+    }
+
+    // `CHIP8::run_frame` with every instruction going through `step`.
+    pub fn run_frame(&mut self, chip: &mut CHIP8, instructions: usize) -> Result<(), EmulationError> {
+        for _ in 0..instructions {
+            self.step(chip)?;
+        }
+        chip.tick_timers();
+        Ok(())
+    }
+
+    // Flushes the trace and hands back the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn trace_line(cycle: u64, chip: &CHIP8) -> String {
+    let pc = chip.pc() as usize;
+    let memory = chip.memory();
+    let opcode = match memory.get(pc..pc + 2) {
+        Some(&[high, low]) => u16::from_be_bytes([high, low]),
+        _ => 0,
+    };
+    let mnemonic = Instruction::decode(memory.get(pc..).unwrap_or(&[])).map_or("???".to_string(), |instruction| instruction.to_string());
+    let registers: Vec<String> = chip.registers().iter().map(|value| format!("{:02X}", value)).collect();
+    format!(
+        "{:010} {:04X} {:04X} {:<width$} V {} I {:04X} SP {:02X} DT {:02X} ST {:02X}",
+        cycle,
+        pc,
+        opcode,
+        mnemonic,
+        registers.join(" "),
+        chip.index(),
+        chip.stack().len(),
+        chip.delay_timer(),
+        chip.sound_timer(),
+        width = MNEMONIC_WIDTH,
+    )
+}
+
+// "200-2FF" or "0x200-0x2FF": an inclusive range of hex addresses.
+pub fn parse_address_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let parse = |address: &str| u16::from_str_radix(address.trim().trim_start_matches("0x"), 16);
+    match s.split_once('-').map(|(start, end)| (parse(start), parse(end))) {
+        Some((Ok(start), Ok(end))) if start <= end => Ok(start..=end),
+        _ => Err(format!("invalid address range '{}', expected START-END in hex", s)),
+    }
+}
+
+// "1000-2000", or "1000-" for everything from cycle 1000 on.
+pub fn parse_cycle_range(s: &str) -> Result<RangeInclusive<u64>, String> {
+    let range = s.split_once('-').and_then(|(start, end)| {
+        let start = start.trim().parse().ok()?;
+        let end = if end.trim().is_empty() { u64::MAX } else { end.trim().parse().ok()? };
+        Some(start..=end).filter(|range| !range.is_empty())
+    });
+    range.ok_or_else(|| format!("invalid cycle range '{}', expected START-END or START-", s))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::quirks::Platform;
+
+    fn trace(chip: &mut CHIP8, mut tracer: Tracer<Vec<u8>>, frames: usize) -> Vec<String> {
+        for _ in 0..frames {
+            tracer.run_frame(chip, 4).unwrap();
+        }
+        String::from_utf8(tracer.finish().unwrap()).unwrap().lines().map(str::to_string).collect()
+    }
+
+    // V0 = 5; V1 = 3; V0 >>= V1; loop.
+    const ROM: &[u8] = &[0x60, 0x05, 0x61, 0x03, 0x80, 0x16, 0x12, 0x06];
+
+    #[test]
+    fn test_format() {
+        // SHR shifts V0 in place, as on SUPER-CHIP.
+        let mut chip = CHIP8::new(Platform::SuperChip);
+        chip.load_from_slice(ROM, None).unwrap();
+        let lines = trace(&mut chip, Tracer::new(Vec::new()), 1);
+        let idle = "00 00 00 00 00 00 00 00 00 00 00 00 00 00";
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], format!("0000000000 0200 6005 LD V0, 0x05        V 00 00 {} I 0000 SP 00 DT 00 ST 00", idle));
+        assert_eq!(lines[2], format!("0000000002 0204 8016 SHR V0, V1         V 05 03 {} I 0000 SP 00 DT 00 ST 00", idle));
+        assert!(lines[3].starts_with("0000000003 0206 1206 JP 0x206           V 02 03"));
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut chip = CHIP8::default();
+        chip.load_from_slice(ROM, None).unwrap();
+        let tracer = Tracer::new(Vec::new()).with_addresses(0x206..=0x206).with_cycles(5..=7);
+        let lines = trace(&mut chip, tracer, 3);
+        let cycles: Vec<&str> = lines.iter().map(|line| &line[..10]).collect();
+        assert_eq!(cycles, ["0000000005", "0000000006", "0000000007"]);
+    }
+
+    #[test]
+    fn test_quirk_divergence() {
+        // The VIP shifts VY into VX, SUPER-CHIP shifts VX in place.
+        let mut vip = CHIP8::new(Platform::CosmacVip);
+        let mut schip = CHIP8::new(Platform::SuperChip);
+        vip.load_from_slice(ROM, None).unwrap();
+        schip.load_from_slice(ROM, None).unwrap();
+        let vip = trace(&mut vip, Tracer::new(Vec::new()), 1);
+        let schip = trace(&mut schip, Tracer::new(Vec::new()), 1);
+        let first_difference = vip.iter().zip(&schip).position(|(a, b)| a != b);
+        assert_eq!(first_difference, Some(3));
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        assert_eq!(parse_address_range("200-2ff"), Ok(0x200..=0x2FF));
+        assert_eq!(parse_address_range("0x300-0x300"), Ok(0x300..=0x300));
+        assert!(parse_address_range("2ff-200").is_err());
+        assert!(parse_address_range("200").is_err());
+        assert_eq!(parse_cycle_range("1000-2000"), Ok(1000..=2000));
+        assert_eq!(parse_cycle_range("1000-"), Ok(1000..=u64::MAX));
+        assert!(parse_cycle_range("-5").is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use crate::{close_tracer, Args, FileTracer, GifSession, MovieSession};
+use chip8::movie::apply_keys;
+use chip8::timer::TimerClock;
+use chip8::{halfblock, EmulationError, CHIP8};
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+    PushKeyboardEnhancementFlags,
+};
+use crossterm::style::Print;
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+// Most terminals only report key presses, with auto-repeat while a key is
+// held. Without release events a key counts as held until this long after
+// its last press or repeat, which bridges the pause before repeat starts.
+const KEY_HOLD: Duration = Duration::from_millis(400);
+
+// Same layout as `scancode_to_keypad` in the SDL frontend, by character
+// since that's all a terminal sends.
+fn char_to_keypad(c: char) -> Option<u8> {
+    match c.to_ascii_lowercase() {
+        '1' => Some(0x1),
+        '2' => Some(0x2),
+        '3' => Some(0x3),
+        '4' => Some(0xC),
+        'q' => Some(0x4),
+        'w' => Some(0x5),
+        'e' => Some(0x6),
+        'r' => Some(0xD),
+        'a' => Some(0x7),
+        's' => Some(0x8),
+        'd' => Some(0x9),
+        'f' => Some(0xE),
+        'z' => Some(0xA),
+        'x' => Some(0x0),
+        'c' => Some(0xB),
+        'v' => Some(0xF),
+        _ => None,
+    }
+}
+
+// Raw mode, the alternate screen and a hidden cursor for as long as this
+// lives, so the terminal is given back even if the emulator panics.
+struct Terminal {
+    release_events: bool,
+}
+
+impl Terminal {
+    fn open() -> io::Result<Terminal> {
+        terminal::enable_raw_mode()?;
+        let release_events = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        let mut stdout = io::stdout();
+        execute!(stdout, EnterAlternateScreen, Hide, Clear(ClearType::All))?;
+        if release_events {
+            execute!(stdout, PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+        }
+        Ok(Terminal { release_events })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        if self.release_events {
+            let _ = execute!(stdout, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(stdout, Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+// Which keypad keys are down: until released, or until KEY_HOLD runs out
+// on terminals that never say.
+struct Keypad {
+    held_until: [Option<Instant>; 16],
+}
+
+impl Keypad {
+    fn press(&mut self, key: u8, now: Instant, release_events: bool) {
+        // Far enough out that only a release event ends it.
+        let hold = if release_events { Duration::from_secs(3600) } else { KEY_HOLD };
+        self.held_until[key as usize] = Some(now + hold);
+    }
+
+    fn release(&mut self, key: u8) {
+        self.held_until[key as usize] = None;
+    }
+
+    // Bit n for key n, as `apply_keys` takes them.
+    fn held(&self, now: Instant) -> u16 {
+        (0..16).filter(|&key| self.held_until[key].is_some_and(|until| until > now)).fold(0, |held, key| held | 1 << key)
+    }
+}
+
+fn status_line(chip: &CHIP8, fps: u32, fault: Option<&EmulationError>) -> String {
+    let registers: Vec<String> = chip.registers().iter().map(|value| format!("{:02X}", value)).collect();
+    let state = match fault {
+        Some(error) => format!("fault: {}", error),
+        None => format!("FPS {}", fps),
+    };
+    format!("PC {:04X} I {:04X} V {} {}  Esc quits", chip.pc(), chip.index(), registers.join(" "), state)
+}
+
+pub fn run(
+    mut chip: CHIP8,
+    args: &Args,
+    mut tracer: Option<FileTracer>,
+    mut movie: Option<MovieSession>,
+    mut gif: Option<GifSession>,
+) -> io::Result<()> {
+    let terminal = Terminal::open()?;
+    let mut stdout = io::stdout();
+    let palette = args.palette();
+    let mut keypad = Keypad { held_until: [None; 16] };
+    let instructions = movie.as_ref().map_or(args.instructions_per_frame, MovieSession::instructions_per_frame);
+
+    let mut clock = TimerClock::default();
+    let mut last_frame = Instant::now();
+    let mut fault: Option<EmulationError> = None;
+    // Only redrawn when the picture changes, which matters over SSH.
+    let mut screen: Vec<String> = Vec::new();
+    let mut beeping = false;
+    let (mut fps, mut frames_this_second, mut second) = (0, 0, Instant::now());
+
+    'main: loop {
+        let now = Instant::now();
+        while event::poll(Duration::ZERO)? {
+            let Event::Key(KeyEvent { code, modifiers, kind, .. }) = event::read()? else {
+                continue;
+            };
+            match (code, kind) {
+                (KeyCode::Esc, _) => break 'main,
+                (KeyCode::Char('c'), _) if modifiers.contains(KeyModifiers::CONTROL) => break 'main,
+                (KeyCode::Char(c), KeyEventKind::Release) => {
+                    if let Some(key) = char_to_keypad(c) {
+                        keypad.release(key);
+                    }
+                }
+                (KeyCode::Char(c), _) => {
+                    if let Some(key) = char_to_keypad(c) {
+                        keypad.press(key, now, terminal.release_events);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let frames = clock.advance(now - last_frame);
+        last_frame = now;
+        if frames == 0 {
+            std::thread::sleep(clock.until_next_tick());
+            continue;
+        }
+
+        for _ in 0..frames {
+            if fault.is_none() {
+                let held = keypad.held(now);
+                let keys = movie.as_ref().map_or(held, |movie| movie.keys(held));
+                apply_keys(&mut chip, keys);
+                let result = match tracer.as_mut() {
+                    Some(tracer) => tracer.run_frame(&mut chip, instructions),
+                    None => chip.run_frame(instructions),
+                };
+                match result {
+                    Ok(()) => {
+                        if let Some(movie) = movie.as_mut() {
+                            movie.after_frame(keys, &chip);
+                        }
+                        frames_this_second += 1;
+                    }
+                    Err(error) => fault = Some(error),
+                }
+            }
+            if let Some(gif) = gif.as_mut() {
+                gif.frame(chip.display());
+            }
+        }
+        if now - second >= Duration::from_secs(1) {
+            (fps, frames_this_second, second) = (frames_this_second, 0, now);
+        }
+
+        // No sound card to play a tone on, so ring the bell as it starts.
+        let sound = fault.is_none() && chip.is_sound_playing();
+        if sound && !beeping {
+            queue!(stdout, Print('\x07'))?;
+        }
+        beeping = sound;
+
+        let lines = halfblock::render(chip.display(), &palette);
+        if lines != screen {
+            if lines.len() != screen.len() {
+                queue!(stdout, Clear(ClearType::All))?;
+            }
+            for (row, line) in lines.iter().enumerate() {
+                queue!(stdout, MoveTo(0, row as u16), Print(line))?;
+            }
+            screen = lines;
+        }
+        queue!(
+            stdout,
+            MoveTo(0, screen.len() as u16),
+            Print(status_line(&chip, fps, fault.as_ref())),
+            Clear(ClearType::UntilNewLine)
+        )?;
+        stdout.flush()?;
+    }
+
+    drop(terminal);
+    if let Some(error) = &fault {
+        eprintln!("CHIP-8 fault: {}", error);
+    }
+    close_tracer(tracer);
+    if let Some(movie) = movie {
+        movie.finish();
+    }
+    if let Some(gif) = gif {
+        gif.finish();
+    }
+    Ok(())
+}
@@ -17,3 +17,17 @@ impl std::ops::IndexMut<u8> for Registers {
         &mut self.0[index as usize]
     }
 }
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Keypad(pub [bool; crate::chip8::KEYPAD_SIZE]);
+impl Keypad {
+    pub fn press(&mut self, key: u8) {
+        self.0[(key & 0xF) as usize] = true;
+    }
+    pub fn release(&mut self, key: u8) {
+        self.0[(key & 0xF) as usize] = false;
+    }
+    // Only the low nibble selects a key, like the original hardware.
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.0[(key & 0xF) as usize]
+    }
+}
@@ -0,0 +1,117 @@
+// Runs test ROMs headlessly and compares the final screen with a golden
+// image in tests/golden, stored as the text `Display` prints.
+//
+// Setting CHIP8_BLESS=1 writes the current screen as the new golden image
+// instead of comparing. Only do that after checking the screen against
+// the result the ROM's documentation gives for a passing interpreter.
+//
+// The corax+, flags, quirks and keypad tests run Timendus' chip8-test-suite
+// (https://github.com/Timendus/chip8-test-suite), whose ROMs go in
+// resources/chip8-test-suite/ next to the suite's license. They are not in
+// the tree yet, so those tests are ignored until the ROMs are added and
+// their golden images recorded.
+use chip8::{Platform, Rng, CHIP8};
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// The suite's ROMs read this byte to skip their start-up menu.
+const MENU_SELECTION: usize = 0x1FF;
+
+struct Run {
+    rom: &'static str,
+    platform: Platform,
+    frames: u64,
+    menu: Option<u8>,
+    // (frame, key, pressed)
+    keys: &'static [(u64, u8, bool)],
+}
+
+impl Run {
+    fn new(rom: &'static str, frames: u64) -> Run {
+        Run { rom, platform: Platform::SuperChip, frames, menu: None, keys: &[] }
+    }
+
+    fn screen(&self) -> String {
+        let mut chip = CHIP8::new(self.platform);
+        chip.set_rng(Rng::new(0));
+        chip.load_font();
+        chip.load_from_file(&Path::new("resources").join(self.rom)).unwrap();
+        if let Some(selection) = self.menu {
+            chip.memory_mut()[MENU_SELECTION] = selection;
+        }
+        for frame in 0..self.frames {
+            for &(_, key, pressed) in self.keys.iter().filter(|(at, _, _)| *at == frame) {
+                if pressed {
+                    chip.key_down(key);
+                } else {
+                    chip.key_up(key);
+                }
+            }
+            chip.run_frame(1000).unwrap_or_else(|error| panic!("{} faulted: {}", self.rom, error));
+        }
+        chip.display().to_string()
+    }
+}
+
+fn assert_golden(name: &str, run: Run) {
+    let path = Path::new("tests/golden").join(format!("{}.txt", name));
+    let screen = run.screen();
+    if env::var_os("CHIP8_BLESS").is_some() {
+        fs::write(&path, &screen).unwrap();
+        return;
+    }
+    let golden = fs::read_to_string(&path)
+        .unwrap_or_else(|error| panic!("no golden image at {} ({}), record one with CHIP8_BLESS=1", path.display(), error));
+    assert!(screen == golden, "{} doesn't match {}:\n{}", run.rom, path.display(), screen);
+}
+
+#[test]
+fn ibm_logo() {
+    assert_golden("ibm_logo", Run::new("ibm_logo.ch8", 10));
+}
+
+// corax89's original opcode test: every instruction it covers should read
+// OK.
+#[test]
+fn corax_opcodes() {
+    for platform in [Platform::CosmacVip, Platform::SuperChip, Platform::XoChip] {
+        assert_golden("corax_opcodes", Run { platform, ..Run::new("test_opcode.ch8", 10) });
+    }
+}
+
+// The suite's extended version of corax89's test.
+#[test]
+#[ignore = "needs resources/chip8-test-suite/3-corax+.ch8"]
+fn corax_plus() {
+    assert_golden("corax_plus", Run::new("chip8-test-suite/3-corax+.ch8", 60));
+}
+
+#[test]
+#[ignore = "needs resources/chip8-test-suite/4-flags.ch8"]
+fn flags() {
+    assert_golden("flags", Run::new("chip8-test-suite/4-flags.ch8", 60));
+}
+
+// Menu entries 1 to 3 check the quirks of CHIP-8, SUPER-CHIP and XO-CHIP,
+// each on the matching platform.
+#[test]
+#[ignore = "needs resources/chip8-test-suite/5-quirks.ch8"]
+fn quirks() {
+    for (platform, menu, name) in [
+        (Platform::CosmacVip, 1, "quirks_vip"),
+        (Platform::SuperChip, 2, "quirks_schip"),
+        (Platform::XoChip, 3, "quirks_xochip"),
+    ] {
+        assert_golden(name, Run { platform, menu: Some(menu), ..Run::new("chip8-test-suite/5-quirks.ch8", 600) });
+    }
+}
+
+// Menu entry 3 waits on FX0A: press and release key 0xA, which the ROM
+// should report as the key it got.
+#[test]
+#[ignore = "needs resources/chip8-test-suite/6-keypad.ch8"]
+fn keypad() {
+    let keys = &[(10, 0xA, true), (20, 0xA, false)];
+    assert_golden("keypad_fx0a", Run { menu: Some(3), keys, ..Run::new("chip8-test-suite/6-keypad.ch8", 60) });
+}